@@ -0,0 +1,342 @@
+// Provenance annotations for the merged output.
+//
+// Comments don't survive a trip through `TokenStream` and rustfmt, so wherever
+// one should go, the merge emits a placeholder item `<marker>!(<index>);` and
+// keeps the annotation itself in a side table. After formatting, `render` swaps
+// each placeholder for its comment. The marker name is picked so that it doesn't
+// occur anywhere in the merged sources, which means user code is never rewritten,
+// and the annotation text never has to be encoded as Rust, so any UTF-8 path works.
+//
+// Rendering also notes the output line each placeholder ended up on, which gives a
+// map from the lines of the output back to the files they came from. Items can
+// get placeholders of their own, so that the map knows where each item starts in
+// its file; those become `// path:first-last` comments, or are dropped.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use proc_macro2::{Literal, TokenStream, TokenTree};
+use quote::{format_ident, quote, ToTokens};
+use regex::Regex;
+
+const MARKER_BASE: &str = "__rustmerge_annotation";
+
+// Where tokens outside of any file are from, like the `mod` items wrapping bundled crates
+pub const GENERATED: &str = "(generated)";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub path: String,  // Path of the original file, relative to the src directory
+    pub file: PathBuf, // The original file itself
+    pub lines: Option<(usize, usize)>, // First and last line of an item, for item annotations
+    pub path_attr: bool, // The file isn't where rustc would look for its module by name
+    pub silent: bool,  // Only starts a region of the line map, without a comment
+}
+
+impl Annotation {
+    pub fn file(path: &str, file: &Path) -> Self {
+        Annotation {
+            path: path.to_string(),
+            file: file.to_path_buf(),
+            lines: None,
+            path_attr: false,
+            silent: false,
+        }
+    }
+
+    // Code the merge wrote itself, which maps to no file
+    pub fn generated() -> Self {
+        Annotation {
+            silent: true,
+            ..Annotation::file(GENERATED, Path::new(""))
+        }
+    }
+
+    pub fn item(path: &str, file: &Path, first: usize, last: usize) -> Self {
+        Annotation {
+            lines: Some((first, last)),
+            ..Annotation::file(path, file)
+        }
+    }
+
+    fn comment(&self) -> String {
+        match self.lines {
+            None => file_comment(&self.path, self.path_attr),
+            Some((first, last)) => item_comment(&self.path, first, last),
+        }
+    }
+}
+
+// `// src/foo.rs`, or `// src/foo.rs (#[path])` for a file its module's
+// declaration has to point at, so that `unmerge` can tell where it goes
+pub fn file_comment(path: &str, path_attr: bool) -> String {
+    if path_attr {
+        format!("// {} (#[path])", path)
+    } else {
+        format!("// {}", path)
+    }
+}
+
+// `// src/foo.rs:120-188`, or `// src/foo.rs:120` for an item on one line
+pub fn item_comment(path: &str, first: usize, last: usize) -> String {
+    if first == last {
+        format!("// {}:{}", path, first)
+    } else {
+        format!("// {}:{}-{}", path, first, last)
+    }
+}
+
+// What items get besides their code
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ItemAnnotations {
+    #[default]
+    None,
+    Tracked,   // Placeholders for the line map, dropped from the output
+    Commented, // Placeholders that become `// path:first-last` comments
+}
+
+#[derive(Debug)]
+pub struct Annotations {
+    marker: String,
+    entries: Vec<Annotation>,
+    items: ItemAnnotations,
+}
+
+// Where a run of output lines comes from: from `output_line` on, the lines follow
+// the original file from its line `first` on, up to `last` when that's known
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub output_line: usize,
+    pub path: String,
+    pub file: PathBuf,
+    pub first: usize,
+    pub last: Option<usize>,
+    pub verbatim: bool, // Whether the lines were copied one for one, rather than formatted
+}
+
+// Lines are numbered from 1, as compilers and editors do
+#[derive(Debug, Clone, Default)]
+pub struct LineMap {
+    regions: Vec<Region>,
+}
+
+// A place in an original file; the column is where the code on the line starts
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub path: String,
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Annotations {
+    // `sources` is all the code that will end up in the output
+    pub fn new<I, S>(sources: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let sources: Vec<S> = sources.into_iter().collect();
+        let mut marker = MARKER_BASE.to_string();
+        let mut n = 0;
+        while sources
+            .iter()
+            .any(|source| source.as_ref().contains(&marker))
+        {
+            n += 1;
+            marker = format!("{}_{}", MARKER_BASE, n);
+        }
+
+        Annotations {
+            marker,
+            entries: Vec::new(),
+            items: ItemAnnotations::None,
+        }
+    }
+
+    pub fn annotate_items(&mut self, items: ItemAnnotations) {
+        self.items = items;
+    }
+
+    // Placeholder for an item of `file` spanning `lines`, if items are annotated
+    pub fn item_marker(&mut self, path: &str, file: &Path, lines: (usize, usize)) -> TokenStream {
+        if self.items == ItemAnnotations::None {
+            return TokenStream::new();
+        }
+        self.marker(Annotation::item(path, file, lines.0, lines.1))
+    }
+
+    // Placeholder item standing in for `annotation` until the code is rendered
+    pub fn marker(&mut self, annotation: Annotation) -> TokenStream {
+        let name = format_ident!("{}", self.marker);
+        let index = Literal::usize_unsuffixed(self.entries.len());
+        self.entries.push(annotation);
+        quote! {
+            #name!(#index);
+        }
+    }
+
+    // Whether an item is one of the placeholders
+    pub fn is_marker(&self, item: &syn::ItemMacro) -> bool {
+        item.ident.is_none() && item.mac.path.is_ident(&self.marker)
+    }
+
+    // Whether an item is the placeholder of another item's annotation
+    pub fn is_item_marker(&self, item: &syn::ItemMacro) -> bool {
+        let tokens: Vec<TokenTree> = item.to_token_stream().into_iter().collect();
+        self.is_marker(item)
+            && self
+                .marked(&tokens)
+                .is_some_and(|annotation| annotation.lines.is_some())
+    }
+
+    // The annotation of the placeholder item `tokens` start with, if they do
+    pub fn marked(&self, tokens: &[TokenTree]) -> Option<&Annotation> {
+        match tokens {
+            [TokenTree::Ident(name), TokenTree::Punct(bang), TokenTree::Group(args), TokenTree::Punct(semi), ..]
+                if *name == self.marker && bang.as_char() == '!' && semi.as_char() == ';' =>
+            {
+                let index = args.stream().to_string().trim().parse::<usize>().ok()?;
+                self.entries.get(index)
+            }
+            _ => None,
+        }
+    }
+
+    // Replace the placeholders in formatted code with their comments, or with
+    // nothing for item placeholders that only track lines, and map the lines of
+    // the result
+    pub fn render(&self, code: &str) -> (String, LineMap) {
+        // The placeholder's trailing newline is consumed and put back, so that a comment
+        // in code rustfmt left alone (`#[rustfmt::skip]`) doesn't swallow the rest of the line
+        let pattern = format!(
+            r"{}\s*!\s*\(\s*(\d+)\s*\)\s*;[ \t]*\n?",
+            regex::escape(&self.marker)
+        );
+        let re = Regex::new(&pattern).unwrap();
+
+        let mut rendered = String::with_capacity(code.len());
+        let mut lines = LineMap::default();
+        let mut position = 0;
+        for caps in re.captures_iter(code) {
+            let whole = caps.get(0).unwrap();
+            rendered.push_str(&code[position..whole.start()]);
+            position = whole.end();
+
+            let Some(annotation) = caps[1]
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| self.entries.get(index))
+            else {
+                rendered.push_str(whole.as_str());
+                continue;
+            };
+            let line = rendered.matches('\n').count() + 1;
+            let (first, last) = match annotation.lines {
+                Some((first, last)) => (first, Some(last)),
+                None => (1, None),
+            };
+            let commented = !annotation.silent
+                && (annotation.lines.is_none() || self.items == ItemAnnotations::Commented);
+            // The comment itself counts as the top of its file or item
+            let output_line = if commented {
+                rendered.push_str(&annotation.comment());
+                rendered.push('\n');
+                line
+            } else {
+                // A placeholder alone on its line goes with its indentation
+                let line_start = rendered.rfind('\n').map_or(0, |i| i + 1);
+                if !whole.as_str().ends_with('\n') {
+                    line
+                } else if rendered[line_start..].trim().is_empty() {
+                    rendered.truncate(line_start);
+                    line
+                } else {
+                    rendered.push('\n');
+                    line + 1
+                }
+            };
+            lines.regions.push(Region {
+                output_line,
+                path: annotation.path.clone(),
+                file: annotation.file.clone(),
+                first,
+                last,
+                verbatim: false,
+            });
+        }
+        rendered.push_str(&code[position..]);
+        (rendered, lines)
+    }
+}
+
+impl LineMap {
+    pub fn new(regions: Vec<Region>) -> Self {
+        LineMap { regions }
+    }
+
+    // The original file behind the path in a file comment
+    pub fn file(&self, path: &str) -> Option<&Path> {
+        self.regions
+            .iter()
+            .find(|region| region.path == path)
+            .map(|region| region.file.as_path())
+    }
+
+    // Where each line of `output`, the code the map was made for, comes from.
+    // Formatting drops blank lines and comments, and may join or split lines, so
+    // unless a region was copied verbatim, its lines are matched by their text, in
+    // order from its start; a line with no match is put right after the last one
+    // that had one. Blank lines come from nowhere.
+    pub fn locate_all(&self, output: &str) -> Vec<Option<Location>> {
+        let output: Vec<&str> = output.lines().collect();
+        let mut sources: HashMap<&Path, Vec<String>> = HashMap::new();
+        let mut located = vec![None; output.len()];
+
+        for (index, region) in self.regions.iter().enumerate() {
+            let end = self
+                .regions
+                .get(index + 1)
+                .map_or(located.len(), |next| next.output_line - 1)
+                .min(located.len());
+            let source = sources.entry(&region.file).or_insert_with(|| {
+                fs::read_to_string(&region.file)
+                    .map(|source| source.lines().map(str::to_string).collect())
+                    .unwrap_or_default()
+            });
+            // Generated code comes from nowhere, and a file may have shrunk since the merge
+            if region.first > source.len() {
+                continue;
+            }
+            let last = region
+                .last
+                .unwrap_or(source.len())
+                .clamp(region.first, source.len());
+
+            let mut next = region.first.min(last);
+            for line in region.output_line..=end {
+                let text = output[line - 1].trim();
+                if text.is_empty() {
+                    continue;
+                }
+                let line_in_source = if region.verbatim {
+                    (region.first + line - region.output_line).min(last)
+                } else {
+                    (next..=last)
+                        .find(|&n| source[n - 1].trim() == text)
+                        .inspect(|&n| next = (n + 1).min(last))
+                        .unwrap_or(next)
+                };
+                let source_line = &source[line_in_source - 1];
+                located[line - 1] = Some(Location {
+                    path: region.path.clone(),
+                    file: region.file.clone(),
+                    line: line_in_source,
+                    column: source_line.len() - source_line.trim_start().len() + 1,
+                });
+            }
+        }
+        located
+    }
+}
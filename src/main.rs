@@ -1,39 +1,192 @@
+mod annotate;
+mod manifest;
+mod markdown;
+mod minify;
+mod patch;
+mod resolver;
+mod rewrite;
+mod size;
+mod sourcemap;
+mod splice;
+mod standalone;
 mod test;
+mod treeshake;
+mod unmerge;
+mod verify;
 
-use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::process::Stdio;
 
+use annotate::{Annotation, Annotations, ItemAnnotations, LineMap};
 use anyhow::{Context, Result};
-use proc_macro2::TokenStream;
-use quote::{quote, ToTokens};
-use syn::Attribute;
+use manifest::{Manifest, PathDependency, Target, TargetKind};
+use proc_macro2::{TokenStream, TokenTree};
+use quote::{format_ident, quote, ToTokens};
+use resolver::{path_attr, ModuleDir};
+use rewrite::CratePaths;
+use size::FileSize;
+use standalone::{ScriptKind, StandaloneManifest};
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
 use syn::File;
-use syn::{Item, ItemMod};
+use syn::{parse_quote, AttrStyle, Attribute, Meta, Token};
+use syn::{Item, ItemMod, Visibility};
 
 #[derive(Debug)]
 struct ModuleInfo {
     content: TokenStream,
-    file_path: PathBuf,       // Absolute path to track module origin
-    rel_path: Option<String>, // Relative path from src directory
+    file_path: PathBuf,            // Absolute path to track module origin
+    rel_path: Option<String>,      // Relative path from src directory
+    children: Vec<String>,         // Keys of submodules, in declaration order
+    vis: Visibility,               // Visibility of the `mod` item declaring this module
+    path_attr: bool,               // Not where rustc looks for it by name, like a `#[path]` file
+    lines: Option<(usize, usize)>, // First and last line of the `mod` item declaring this module
 }
+
+// Another crate merged into the bundle as a top-level module named after it
 #[derive(Debug)]
+struct EmbeddedCrate {
+    name: String,         // Name of the crate, and of its module in the bundle
+    aliases: Vec<String>, // Other names dependents use for it (renamed dependencies)
+    src_dir: PathBuf,
+    module_structure: HashMap<String, ModuleInfo>,
+}
+#[derive(Debug, Clone, Default)]
 struct Args {
     package_name: Option<String>,
     output_path: Option<PathBuf>,
     process_all: bool,
+    preserve_comments: bool,
+    formatter: Formatter,
+    target: Option<TargetSelection>,
+    inline_lib: bool,  // Bundle the package's library into its binaries
+    bundle_deps: bool, // Bundle the package's path dependencies
+    tree_shake: bool,  // Drop items the entry point can't reach
+    minify: bool,
+    shorten_idents: bool, // Rename local variables when minifying
+    max_bytes: Option<usize>,
+    max_tokens: Option<usize>,
+    format: OutputFormat,
+    emit_crate: Option<PathBuf>, // Write a crate of its own there instead of a file
+    verify: bool,                // Type-check the output once it's written
+    source_map: bool,            // Write where the output's lines come from next to it
+    annotate_items: bool,        // Comment each item with its original lines
+    apply: Option<PathBuf>,      // Edited merged file to take back to the sources
+    write: bool,                 // Make the changes `apply` finds instead of printing them
+    check: bool,                 // Compare with the existing output instead of writing it
+    stdout: bool,                // Write the code to stdout, and status lines to stderr
+}
+
+// Status lines go to stdout, unless the merged code does
+macro_rules! status {
+    ($args:expr, $($arg:tt)*) => {
+        if $args.stdout {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+// A merged target, how much of it comes from each file, and where its lines come from
+struct Merged {
+    code: String,
+    document: Option<String>, // What `--format` makes of the code: a script with its manifest, or markdown
+    files: Vec<FileSize>,
+    lines: LineMap, // For `code`
+}
+
+impl Merged {
+    // What goes into the output file
+    fn output(&self) -> &str {
+        self.document.as_deref().unwrap_or(&self.code)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TargetSelection {
+    Lib,
+    Bin(String),
+    Bins,       // Every binary target, one output file each
+    AllTargets, // The library and every binary target
+}
+
+impl TargetSelection {
+    fn is_multiple(&self) -> bool {
+        matches!(self, TargetSelection::Bins | TargetSelection::AllTargets)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum OutputFormat {
+    #[default]
+    Rust,
+    CargoScript, // Runs with `cargo +nightly -Zscript`
+    RustScript,  // Runs with rust-script
+    Markdown,    // A document with a code block per file
+}
+
+impl OutputFormat {
+    fn script_kind(&self) -> Option<ScriptKind> {
+        match self {
+            OutputFormat::Rust | OutputFormat::Markdown => None,
+            OutputFormat::CargoScript => Some(ScriptKind::Cargo),
+            OutputFormat::RustScript => Some(ScriptKind::RustScript),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Markdown => "md",
+            _ => "rs",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum Formatter {
+    #[default]
+    Auto, // rustfmt, falling back to the built-in formatter
+    Rustfmt,
+    Builtin,
+    None,
+}
+
+#[derive(Debug, Clone)]
+struct FormatOptions {
+    formatter: Formatter,
+    edition: String,                 // Edition of the package being merged
+    rustfmt_config: Option<PathBuf>, // The package's rustfmt.toml, if it has one
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            formatter: Formatter::Auto,
+            edition: "2021".to_string(),
+            rustfmt_config: None,
+        }
+    }
 }
 
 fn main() -> Result<()> {
+    if env::args().nth(2).as_deref() == Some("unmerge") {
+        let (merged_file, into) = parse_unmerge_args();
+        return unmerge_file(&merged_file, &into);
+    }
     let args = parse_args()?;
 
     let current_dir = env::current_dir().context("Failed to get current directory")?;
 
+    if let Some(edited_file) = &args.apply {
+        let (_, package_path) = determine_package(&current_dir, &args.package_name)?;
+        return apply_edits(&package_path, edited_file, &args);
+    }
     if args.process_all {
         process_all_packages(&current_dir, &args)?;
     } else {
@@ -47,29 +200,171 @@ fn main() -> Result<()> {
 fn parse_args() -> Result<Args> {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 || args[1] != "rustmerge" {
-        eprintln!("Usage: cargo rustmerge [--all] [<package_name>] [--output <path>]");
+        eprintln!(
+            "Usage: cargo rustmerge [--all] [<package_name>] [--output <path> | -] [--lib | --bin <name> | --bins | --all-targets] [--stdout] [--inline-lib] [--bundle-deps] [--tree-shake] [--minify [--shorten-idents]] [--max-bytes <n>] [--max-tokens <n>] [--preserve-comments] [--formatter rustfmt|builtin|none] [--format rust|cargo-script|rust-script|markdown] [--emit-crate <dir>] [--verify] [--source-map] [--annotate-items] [--check]\n       cargo rustmerge unmerge <merged.rs> --into <dir>\n       cargo rustmerge apply <edited.rs> [<package_name>] [<merge options>] [--output <patch>] [--write]"
+        );
         std::process::exit(1);
     }
 
     let mut package_name = None;
     let mut output_path = None;
     let mut process_all = false;
+    let mut preserve_comments = false;
+    let mut formatter = Formatter::Auto;
+    let mut target = None;
+    let mut inline_lib = false;
+    let mut bundle_deps = false;
+    let mut tree_shake = false;
+    let mut minify = false;
+    let mut shorten_idents = false;
+    let mut max_bytes = None;
+    let mut max_tokens = None;
+    let mut format = OutputFormat::Rust;
+    let mut emit_crate = None;
+    let mut verify = false;
+    let mut source_map = false;
+    let mut annotate_items = false;
+    let mut apply = None;
+    let mut write = false;
+    let mut check = false;
+    let mut stdout = false;
     let mut i = 2;
 
+    // `apply <edited.rs>`, followed by the options the file was merged with
+    if args.get(2).map(String::as_str) == Some("apply") {
+        match args.get(3) {
+            Some(edited_file) => apply = Some(PathBuf::from(edited_file)),
+            None => {
+                eprintln!("Error: apply requires the edited merged file");
+                std::process::exit(1);
+            }
+        }
+        i = 4;
+    }
+
     while i < args.len() {
         match args[i].as_str() {
             "--output" => {
                 i += 1;
-                if i < args.len() {
+                if i < args.len() && args[i] == "-" {
+                    stdout = true;
+                } else if i < args.len() {
                     output_path = Some(PathBuf::from(&args[i]));
                 } else {
                     eprintln!("Error: --output option requires a path");
                     std::process::exit(1);
                 }
             }
+            "--emit-crate" => {
+                i += 1;
+                if i < args.len() {
+                    emit_crate = Some(PathBuf::from(&args[i]));
+                } else {
+                    eprintln!("Error: --emit-crate option requires a directory");
+                    std::process::exit(1);
+                }
+            }
             "--all" => {
                 process_all = true;
             }
+            "--lib" => {
+                target = Some(TargetSelection::Lib);
+            }
+            "--bin" => {
+                i += 1;
+                if i < args.len() {
+                    target = Some(TargetSelection::Bin(args[i].clone()));
+                } else {
+                    eprintln!("Error: --bin option requires a target name");
+                    std::process::exit(1);
+                }
+            }
+            "--bins" => {
+                target = Some(TargetSelection::Bins);
+            }
+            "--all-targets" => {
+                target = Some(TargetSelection::AllTargets);
+            }
+            "--inline-lib" => {
+                inline_lib = true;
+            }
+            "--bundle-deps" => {
+                bundle_deps = true;
+            }
+            "--tree-shake" => {
+                tree_shake = true;
+            }
+            "--minify" => {
+                minify = true;
+            }
+            "--verify" => {
+                verify = true;
+            }
+            "--source-map" => {
+                source_map = true;
+            }
+            "--annotate-items" => {
+                annotate_items = true;
+            }
+            "--write" => {
+                write = true;
+            }
+            "--check" => {
+                check = true;
+            }
+            "--stdout" => {
+                stdout = true;
+            }
+            "--shorten-idents" => {
+                shorten_idents = true;
+            }
+            "--max-bytes" | "--max-tokens" => {
+                let limit = match args.get(i + 1).and_then(|n| n.parse::<usize>().ok()) {
+                    Some(limit) => limit,
+                    None => {
+                        eprintln!("Error: {} option requires a number", args[i]);
+                        std::process::exit(1);
+                    }
+                };
+                if args[i] == "--max-bytes" {
+                    max_bytes = Some(limit);
+                } else {
+                    max_tokens = Some(limit);
+                }
+                i += 1;
+            }
+            "--preserve-comments" => {
+                preserve_comments = true;
+            }
+            "--format" => {
+                i += 1;
+                format = match args.get(i).map(String::as_str) {
+                    Some("rust") => OutputFormat::Rust,
+                    Some("cargo-script") => OutputFormat::CargoScript,
+                    Some("rust-script") => OutputFormat::RustScript,
+                    Some("markdown") => OutputFormat::Markdown,
+                    _ => {
+                        eprintln!(
+                            "Error: --format option requires one of rust, cargo-script, rust-script, markdown"
+                        );
+                        std::process::exit(1);
+                    }
+                };
+            }
+            "--formatter" => {
+                i += 1;
+                formatter = match args.get(i).map(String::as_str) {
+                    Some("rustfmt") => Formatter::Rustfmt,
+                    Some("builtin") => Formatter::Builtin,
+                    Some("none") => Formatter::None,
+                    _ => {
+                        eprintln!(
+                            "Error: --formatter option requires one of rustfmt, builtin, none"
+                        );
+                        std::process::exit(1);
+                    }
+                };
+            }
             _ => {
                 if package_name.is_none() {
                     package_name = Some(args[i].clone());
@@ -86,10 +381,177 @@ fn parse_args() -> Result<Args> {
         package_name,
         output_path,
         process_all,
+        preserve_comments,
+        formatter,
+        target,
+        inline_lib,
+        bundle_deps,
+        tree_shake,
+        minify,
+        shorten_idents,
+        max_bytes,
+        max_tokens,
+        format,
+        emit_crate,
+        verify,
+        source_map,
+        annotate_items,
+        apply,
+        write,
+        check,
+        stdout,
     })
 }
 
+// `cargo rustmerge unmerge <merged.rs> --into <dir>`
+fn parse_unmerge_args() -> (PathBuf, PathBuf) {
+    let args: Vec<String> = env::args().skip(3).collect();
+    let mut merged_file = None;
+    let mut into = None;
+    let mut i = 0;
+
+    while i < args.len() {
+        match args[i].as_str() {
+            "--into" => {
+                i += 1;
+                if i < args.len() {
+                    into = Some(PathBuf::from(&args[i]));
+                } else {
+                    eprintln!("Error: --into option requires a directory");
+                    std::process::exit(1);
+                }
+            }
+            _ => {
+                if merged_file.is_none() {
+                    merged_file = Some(PathBuf::from(&args[i]));
+                } else {
+                    eprintln!("Error: Unexpected argument '{}'", args[i]);
+                    std::process::exit(1);
+                }
+            }
+        }
+        i += 1;
+    }
+
+    match (merged_file, into) {
+        (Some(merged_file), Some(into)) => (merged_file, into),
+        _ => {
+            eprintln!("Usage: cargo rustmerge unmerge <merged.rs> --into <dir>");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Split a merged file back into the source files it was made of, under `into`
+fn unmerge_file(merged_file: &Path, into: &Path) -> Result<()> {
+    let merged = fs::read_to_string(merged_file)
+        .with_context(|| format!("Failed to read {:?}", merged_file))?;
+    let files = unmerge::unmerge(&merged)
+        .with_context(|| format!("Failed to unmerge {:?}", merged_file))?;
+    for file in &files {
+        write_output(&into.join(&file.path), &file.text)?;
+    }
+    println!(
+        "Unmerged {:?} into {} files in {:?}",
+        merged_file,
+        files.len(),
+        into
+    );
+    Ok(())
+}
+
+// Take the edits made to a merged file back to the files of the package: as a
+// unified diff, printed or written to `--output`, or made in place with `--write`
+fn apply_edits(package_path: &Path, edited_file: &Path, args: &Args) -> Result<()> {
+    if args.process_all
+        || args.emit_crate.is_some()
+        || args.minify
+        || args.format == OutputFormat::Markdown
+    {
+        return Err(anyhow::anyhow!(
+            "apply can't be combined with --all, --emit-crate, --minify or --format markdown"
+        ));
+    }
+    // Printing the patch takes stdout for itself
+    let args = &Args {
+        stdout: !args.write && args.output_path.is_none(),
+        ..args.clone()
+    };
+    let manifest = Manifest::load(package_path)?;
+    let target = select_target(&manifest, args.target.as_ref(), args.inline_lib)?;
+    let src_dir = target_src_dir(package_path, &target)?;
+    let merged = merge_target(&manifest, package_path, &target, args)?;
+    let edited = fs::read_to_string(edited_file)
+        .with_context(|| format!("Failed to read {:?}", edited_file))?;
+
+    let patches = patch::file_patches(merged.output(), &edited, |path| {
+        merged
+            .lines
+            .file(path)
+            .map_or_else(|| src_dir.join(path), Path::to_path_buf)
+    })
+    .with_context(|| format!("Failed to apply {:?}", edited_file))?;
+
+    if args.write {
+        for file_patch in &patches {
+            match &file_patch.patched {
+                Some(text) => write_output(&file_patch.file, text)?,
+                None => fs::remove_file(&file_patch.file)
+                    .with_context(|| format!("Failed to remove {:?}", file_patch.file))?,
+            }
+        }
+        println!(
+            "Applied the edits in {:?} to {} files",
+            edited_file,
+            patches.len()
+        );
+        for file_patch in &patches {
+            let change = match (&file_patch.original, &file_patch.patched) {
+                (None, _) => "created",
+                (_, None) => "removed",
+                _ => "modified",
+            };
+            println!("  {}: {:?}", change, file_patch.file);
+        }
+        return Ok(());
+    }
+
+    // Paths in the diff are relative to where it's run, for `git apply` or `patch -p1`
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let diff: String = patches
+        .iter()
+        .map(|file_patch| {
+            let label = file_patch
+                .file
+                .strip_prefix(&current_dir)
+                .unwrap_or(&file_patch.file);
+            patch::unified_diff(
+                &label.to_string_lossy(),
+                file_patch.original.as_deref(),
+                file_patch.patched.as_deref(),
+            )
+        })
+        .collect();
+    match &args.output_path {
+        Some(output_file) => {
+            write_output(output_file, &diff)?;
+            println!(
+                "Patch for {} files written to {:?}",
+                patches.len(),
+                output_file
+            );
+        }
+        None => print!("{}", diff),
+    }
+    Ok(())
+}
+
 fn process_all_packages(workspace_root: &Path, args: &Args) -> Result<()> {
+    if args.stdout {
+        return Err(anyhow::anyhow!(
+            "--output - writes a single target, it can't be combined with --all"
+        ));
+    }
     let cargo_toml = workspace_root.join("Cargo.toml");
     let content = fs::read_to_string(cargo_toml)?;
     let parsed_toml: toml::Value = toml::from_str(&content)?;
@@ -100,28 +562,51 @@ fn process_all_packages(workspace_root: &Path, args: &Args) -> Result<()> {
             .and_then(|m| m.as_array())
             .context("Failed to get workspace members")?;
 
+        let mut failed = Vec::new();
         for member in members {
-            let output_path = if args.output_path.is_some() {
+            let output_path = args.output_path.as_ref().map(|output_dir| {
                 let member_to_name = member.as_str().unwrap().replace("/", "_");
-                Some(
-                    args.output_path
-                        .as_ref()
-                        .unwrap()
-                        .join(member_to_name)
-                        .with_extension("rs"),
-                )
-            } else {
-                None
-            };
+                // With several targets per member, each member gets a directory instead
+                if args
+                    .target
+                    .as_ref()
+                    .is_some_and(TargetSelection::is_multiple)
+                {
+                    output_dir.join(member_to_name)
+                } else {
+                    output_dir.join(member_to_name).with_extension("rs")
+                }
+            });
+
+            let emit_crate = args
+                .emit_crate
+                .as_ref()
+                .map(|crates_dir| crates_dir.join(member.as_str().unwrap().replace("/", "_")));
 
             let args_with_output = Args {
                 output_path,
+                emit_crate,
                 process_all: false,
                 package_name: None,
+                ..args.clone()
             };
             let package_name = member.as_str().unwrap();
             let package_path = workspace_root.join(package_name);
-            process_single_package(package_name, &package_path, &args_with_output)?;
+            let result = process_single_package(package_name, &package_path, &args_with_output);
+            // A check goes through every member before failing
+            match result {
+                Err(err) if args.check => {
+                    eprintln!("Error: {:#}", err);
+                    failed.push(package_name);
+                }
+                result => result?,
+            }
+        }
+        if !failed.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Check failed for packages: {}",
+                failed.join(", ")
+            ));
         }
     } else {
         // If it's not a workspace, process the single package
@@ -133,28 +618,512 @@ fn process_all_packages(workspace_root: &Path, args: &Args) -> Result<()> {
 }
 
 fn process_single_package(package_name: &str, package_path: &Path, args: &Args) -> Result<()> {
-    let src_dir = find_src_dir(package_path)?;
-    let output_file = args.output_path.clone().unwrap_or_else(|| {
-        let output_path = env::current_dir().unwrap().join("target").join("rustmerge");
-        create_output_file(&output_path, package_name)
-    });
+    let multiple = args
+        .target
+        .as_ref()
+        .is_some_and(TargetSelection::is_multiple);
+    if args.stdout && (multiple || args.emit_crate.is_some() || args.check || args.source_map) {
+        return Err(anyhow::anyhow!(
+            "--output - writes a single target, it can't be combined with --bins, --all-targets, --emit-crate, --check or --source-map"
+        ));
+    }
+    let manifest = Manifest::load(package_path)?;
+    let default_output_dir = || env::current_dir().unwrap().join("target").join("rustmerge");
+
+    if let Some(crate_dir) = &args.emit_crate {
+        return emit_crate(&manifest, package_name, package_path, crate_dir, args);
+    }
+
+    if let Some(selection) = args.target.as_ref().filter(|s| s.is_multiple()) {
+        // One file per target, in `--output <dir>` or `target/rustmerge/<package>/`
+        let output_dir = args
+            .output_path
+            .clone()
+            .unwrap_or_else(|| default_output_dir().join(package_name));
+        let mut outputs = Vec::new();
+        for target in select_targets(&manifest, selection)? {
+            let merged = merge_target(&manifest, package_path, &target, args)?;
+            let output_file = output_dir.join(target_file_name(&target, args.format));
+            if args.check {
+                outputs.extend(checked_outputs(&output_file, &merged, args));
+                continue;
+            }
+            write_output(&output_file, merged.output())?;
+            println!(
+                "Merged and {} {} target '{}' of package '{}' created in {:?}",
+                merged_kind(args),
+                target.kind,
+                target.name,
+                package_name,
+                output_file
+            );
+            if args.source_map {
+                write_source_map(&output_file, &merged)?;
+            }
+            println!("File size: {} bytes", fs::metadata(&output_file)?.len());
+            if args.verify {
+                verify_merged(&manifest, &target, &merged, args)?;
+            }
+            check_size_limits(&merged, args)?;
+        }
+        return check_outputs(&outputs);
+    }
+
+    let target = select_target(&manifest, args.target.as_ref(), args.inline_lib)?;
+    let merged = merge_target(&manifest, package_path, &target, args)?;
+    if args.stdout {
+        io::stdout()
+            .write_all(merged.output().as_bytes())
+            .context("Failed to write the merged code to stdout")?;
+        status!(
+            args,
+            "Merged and {} Rust program for package '{}' written to stdout",
+            merged_kind(args),
+            package_name
+        );
+    } else {
+        let output_file = args.output_path.clone().unwrap_or_else(|| {
+            create_output_file(&default_output_dir(), package_name, args.format)
+        });
+        if args.check {
+            return check_outputs(&checked_outputs(&output_file, &merged, args));
+        }
+        write_output(&output_file, merged.output())?;
+        println!(
+            "Merged and {} Rust program for package '{}' created in {:?}",
+            merged_kind(args),
+            package_name,
+            output_file
+        );
+        if args.source_map {
+            write_source_map(&output_file, &merged)?;
+        }
+    }
+    status!(args, "File size: {} bytes", merged.output().len());
+    if args.verify {
+        verify_merged(&manifest, &target, &merged, args)?;
+    }
+    check_size_limits(&merged, args)?;
 
-    let module_structure = parse_module_structure(&src_dir)?;
-    let merged_content = process_package(&src_dir, &module_structure)?;
+    Ok(())
+}
 
-    let formatted_content = format_rust_code(&merged_content.to_string())?;
+// Write the merged target as a crate of its own, with a Cargo.toml that has what
+// the package's has, minus the rest of the workspace
+fn emit_crate(
+    manifest: &Manifest,
+    package_name: &str,
+    package_path: &Path,
+    crate_dir: &Path,
+    args: &Args,
+) -> Result<()> {
+    if args
+        .target
+        .as_ref()
+        .is_some_and(TargetSelection::is_multiple)
+    {
+        return Err(anyhow::anyhow!(
+            "--emit-crate writes a single target, pick one with --lib or --bin <name>"
+        ));
+    }
+    if args.output_path.is_some() || args.format != OutputFormat::Rust {
+        return Err(anyhow::anyhow!(
+            "--emit-crate can't be combined with --output or --format"
+        ));
+    }
 
-    fs::create_dir_all(output_file.parent().unwrap())?;
-    fs::write(&output_file, formatted_content)?;
+    let target = select_target(manifest, args.target.as_ref(), args.inline_lib)?;
+    let merged = merge_target(manifest, package_path, &target, args)?;
+    let standalone = StandaloneManifest::new(manifest, &target, args.bundle_deps, args.inline_lib);
+    let root_file = crate_dir.join(standalone::root_file(&target));
+    if args.check {
+        let mut outputs = vec![(crate_dir.join("Cargo.toml"), standalone.cargo_toml(&target))];
+        outputs.extend(checked_outputs(&root_file, &merged, args));
+        return check_outputs(&outputs);
+    }
+    write_output(
+        &crate_dir.join("Cargo.toml"),
+        &standalone.cargo_toml(&target),
+    )?;
+    write_output(&root_file, merged.output())?;
     println!(
-        "Merged and formatted Rust program for package '{}' created in {:?}",
-        package_name, output_file
+        "Crate for {} target '{}' of package '{}' created in {:?}",
+        target.kind, target.name, package_name, crate_dir
+    );
+    if args.source_map {
+        write_source_map(&root_file, &merged)?;
+    }
+    println!("File size: {} bytes", fs::metadata(&root_file)?.len());
+    if args.verify {
+        verify_merged(manifest, &target, &merged, args)?;
+    }
+    check_size_limits(&merged, args)
+}
+
+// Write `<output>.map.json`, the source map of an output file
+fn write_source_map(output_file: &Path, merged: &Merged) -> Result<()> {
+    let (map_file, source_map) = source_map_file(output_file, merged);
+    write_output(&map_file, &source_map)?;
+    println!("Source map written to {:?}", map_file);
+    Ok(())
+}
+
+fn source_map_file(output_file: &Path, merged: &Merged) -> (PathBuf, String) {
+    let file_name = output_file
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let map_file = output_file.with_file_name(format!("{}.map.json", file_name));
+    // A script's code comes after its manifest
+    let line_offset = merged.output().lines().count() - merged.code.lines().count();
+    let source_map = sourcemap::source_map(
+        &file_name,
+        &merged.lines.locate_all(&merged.code),
+        line_offset,
+    );
+    (map_file, source_map)
+}
+
+// With `--check`, the files a merge would write are compared with what's there
+// instead, and any that differ or are missing make it fail
+fn check_outputs(outputs: &[(PathBuf, String)]) -> Result<()> {
+    let mut stale = 0;
+    for (output_file, content) in outputs {
+        match fs::read_to_string(output_file) {
+            Ok(existing) if existing == *content => {
+                println!("{:?} is up to date", output_file);
+            }
+            Ok(existing) => {
+                let (added, removed, line) = patch::diff_summary(&existing, content);
+                println!(
+                    "{:?} is out of date: +{} -{} lines, the first change at line {}",
+                    output_file, added, removed, line
+                );
+                stale += 1;
+            }
+            Err(_) => {
+                println!("{:?} is missing", output_file);
+                stale += 1;
+            }
+        }
+    }
+    if stale > 0 {
+        return Err(anyhow::anyhow!(
+            "Merged output is out of date (files: {}); run without --check to update it",
+            stale
+        ));
+    }
+    Ok(())
+}
+
+// What `--check` compares for an output file: the file, and its source map
+fn checked_outputs(output_file: &Path, merged: &Merged, args: &Args) -> Vec<(PathBuf, String)> {
+    let mut outputs = vec![(output_file.to_path_buf(), merged.output().to_string())];
+    if args.source_map {
+        outputs.push(source_map_file(output_file, merged));
+    }
+    outputs
+}
+
+// Type-check the merged target, and show the errors at their place in the sources
+fn verify_merged(manifest: &Manifest, target: &Target, merged: &Merged, args: &Args) -> Result<()> {
+    let standalone = StandaloneManifest::new(manifest, target, args.bundle_deps, args.inline_lib);
+    let diagnostics = verify::check(
+        &standalone,
+        target,
+        &merged.code,
+        &merged.lines,
+        manifest.lockfile().as_deref(),
+    )?;
+
+    let errors: Vec<_> = diagnostics.iter().filter(|d| d.is_error()).collect();
+    for error in &errors {
+        match &error.origin {
+            Some(origin) => eprintln!(
+                "{}:{}: {} (merged line {}:{})",
+                origin.path, origin.line, error.message, error.line, error.column
+            ),
+            None => eprintln!(
+                "merged line {}:{}: {}",
+                error.line, error.column, error.message
+            ),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Merged {} target '{}' does not compile (errors: {})",
+            target.kind,
+            target.name,
+            errors.len()
+        ));
+    }
+    status!(
+        args,
+        "Verified {} target '{}': it type-checks (warnings: {})",
+        target.kind,
+        target.name,
+        diagnostics.len()
+    );
+    Ok(())
+}
+
+// Fail when the output is over `--max-bytes` or `--max-tokens`, after showing
+// where its size comes from. The output has been written by then, so it can be
+// looked at.
+fn check_size_limits(merged: &Merged, args: &Args) -> Result<()> {
+    let mut exceeded = Vec::new();
+    if let Some(max_bytes) = args.max_bytes {
+        let bytes = merged.output().len();
+        if bytes > max_bytes {
+            exceeded.push(format!("{} bytes (--max-bytes {})", bytes, max_bytes));
+        }
+    }
+    if let Some(max_tokens) = args.max_tokens {
+        // Markdown isn't Rust, so only its code is counted
+        let counted = match args.format {
+            OutputFormat::Markdown => &merged.code,
+            _ => merged.output(),
+        };
+        let tokens = size::count_tokens(counted)?;
+        if tokens > max_tokens {
+            exceeded.push(format!("{} tokens (--max-tokens {})", tokens, max_tokens));
+        }
+    }
+    if exceeded.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("Size by file:");
+    for file in &merged.files {
+        eprintln!(
+            "  {:>9} bytes {:>8} tokens  {}",
+            file.bytes, file.tokens, file.path
+        );
+    }
+    Err(anyhow::anyhow!(
+        "Merged output is over the size limit: {}",
+        exceeded.join(", ")
+    ))
+}
+
+fn merge_target(
+    manifest: &Manifest,
+    package_path: &Path,
+    target: &Target,
+    args: &Args,
+) -> Result<Merged> {
+    let src_dir = target_src_dir(package_path, target)?;
+
+    let mut embedded = Vec::new();
+    if args.bundle_deps {
+        embed_path_dependencies(manifest, &mut embedded)?;
+    }
+    if args.inline_lib && target.kind == TargetKind::Bin {
+        let lib = manifest
+            .lib_target()
+            .context("--inline-lib requires the package to have a library target")?;
+        let lib_src_dir = target_src_dir(package_path, &lib)?;
+        embedded.push(EmbeddedCrate {
+            module_structure: parse_embedded_crate(&lib.path, &lib_src_dir)?,
+            name: lib.name,
+            aliases: Vec::new(),
+            src_dir: lib_src_dir,
+        });
+    }
+
+    if args.shorten_idents && !args.minify {
+        return Err(anyhow::anyhow!("--shorten-idents requires --minify"));
+    }
+    if args.source_map && args.minify {
+        return Err(anyhow::anyhow!(
+            "--source-map can't be combined with --minify, which puts everything on one line"
+        ));
+    }
+    if args.format == OutputFormat::Markdown && (args.minify || args.source_map) {
+        return Err(anyhow::anyhow!(
+            "--format markdown can't be combined with --minify, which drops the file comments it splits the code at, or --source-map"
+        ));
+    }
+
+    let mut merged = if args.preserve_comments {
+        if !embedded.is_empty() || args.tree_shake || args.minify {
+            return Err(anyhow::anyhow!(
+                "--preserve-comments can't be combined with --inline-lib, --bundle-deps, --tree-shake or --minify"
+            ));
+        }
+        // Splicing keeps the original layout, so the output is not reformatted
+        let (code, lines) = splice::merge_source_text(&target.path, &src_dir, args.annotate_items)?;
+        let files = size::source_file_sizes(&parse_crate(&target.path, &src_dir)?)?;
+        Merged {
+            code,
+            document: None,
+            files,
+            lines,
+        }
+    } else {
+        let format_options = FormatOptions {
+            formatter: args.formatter,
+            edition: manifest.edition(),
+            rustfmt_config: find_rustfmt_config(package_path),
+        };
+        let module_structure = parse_crate(&target.path, &src_dir)?;
+        // The line map needs to know where items start
+        let items = if args.annotate_items {
+            ItemAnnotations::Commented
+        } else if args.verify || args.source_map {
+            ItemAnnotations::Tracked
+        } else {
+            ItemAnnotations::None
+        };
+        let (mut merged_content, annotations) =
+            merge_tokens(&src_dir, &module_structure, &embedded, items)?;
+        if args.tree_shake {
+            let shaken = treeshake::tree_shake(merged_content, &annotations)
+                .context("Failed to tree-shake the merged code")?;
+            status!(
+                args,
+                "Tree shaking removed {} unreachable items from {} target '{}'",
+                shaken.removed.len(),
+                target.kind,
+                target.name
+            );
+            for item in &shaken.removed {
+                status!(args, "  {}", item);
+            }
+            merged_content = shaken.content;
+        }
+        let (mut code, mut lines) = render_merged(&merged_content, &annotations, &format_options)?;
+        if args.minify {
+            // The formatted size is what the minified file gets compared against
+            let minified = minify::minify(&merged_content, &annotations, args.shorten_idents)
+                .context("Failed to minify the merged code")?;
+            status!(
+                args,
+                "Minified {} target '{}' from {} to {} bytes",
+                target.kind,
+                target.name,
+                code.len(),
+                minified.len()
+            );
+            code = minified;
+            lines = LineMap::default();
+        }
+        let files = size::file_sizes(&merged_content, &annotations, &code);
+        Merged {
+            code,
+            document: None,
+            files,
+            lines,
+        }
+    };
+
+    if let Some(kind) = args.format.script_kind() {
+        let standalone =
+            StandaloneManifest::new(manifest, target, args.bundle_deps, args.inline_lib);
+        merged.document = Some(standalone.embed(&merged.code, kind));
+    }
+    if args.format == OutputFormat::Markdown {
+        merged.document = Some(markdown_document(manifest, package_path, target, &merged)?);
+    }
+    Ok(merged)
+}
+
+// The merged code as a markdown document, one section per file, headed by its
+// path in the package
+fn markdown_document(
+    manifest: &Manifest,
+    package_path: &Path,
+    target: &Target,
+    merged: &Merged,
+) -> Result<String> {
+    let files =
+        unmerge::unmerge(&merged.code).context("Failed to split the merged code into files")?;
+    let sections: Vec<markdown::Section> = files
+        .iter()
+        .map(|file| {
+            let path = file.path.to_string_lossy();
+            let heading = merged
+                .lines
+                .file(&path)
+                .and_then(|original| original.strip_prefix(package_path).ok())
+                .map_or_else(
+                    || path.to_string(),
+                    |relative| relative.to_string_lossy().to_string(),
+                );
+            markdown::Section { heading, file }
+        })
+        .collect();
+
+    let mut title = manifest.package_name().unwrap_or("merged").to_string();
+    if let Some(version) = manifest.package_field("version").and_then(|v| v.as_str()) {
+        title = format!("{} {}", title, version);
+    }
+    let summary = format!(
+        "The {} target `{}`, merged from {} files.",
+        target.kind,
+        target.name,
+        sections.len()
     );
-    println!("File size: {} bytes", fs::metadata(&output_file)?.len());
+    Ok(markdown::document(&title, &summary, &sections))
+}
+
+// Embed the path dependencies of a package, and theirs, each after its own dependencies
+fn embed_path_dependencies(manifest: &Manifest, embedded: &mut Vec<EmbeddedCrate>) -> Result<()> {
+    for PathDependency {
+        package_dir,
+        rename,
+    } in manifest.path_dependencies()
+    {
+        let dependency = Manifest::load(&package_dir)
+            .with_context(|| format!("Failed to load path dependency {:?}", package_dir))?;
+        let lib = dependency
+            .lib_target()
+            .with_context(|| format!("Path dependency {:?} has no library target", package_dir))?;
+
+        if let Some(existing) = embedded.iter_mut().find(|krate| krate.name == lib.name) {
+            let existing_root = existing
+                .module_structure
+                .get("crate")
+                .and_then(|root| root.file_path.canonicalize().ok());
+            if existing_root != lib.path.canonicalize().ok() {
+                return Err(anyhow::anyhow!(
+                    "Two different path dependencies are named '{}'",
+                    lib.name
+                ));
+            }
+            existing.aliases.extend(rename);
+            continue;
+        }
 
+        embed_path_dependencies(&dependency, embedded)?;
+        // File comments show the dependency's directory, e.g. `// utils/src/lib.rs (#[path])`
+        let src_dir = package_dir.parent().unwrap_or(&package_dir).to_path_buf();
+        embedded.push(EmbeddedCrate {
+            module_structure: parse_embedded_crate(&lib.path, &src_dir)?,
+            name: lib.name,
+            aliases: rename.into_iter().collect(),
+            src_dir,
+        });
+    }
     Ok(())
 }
 
+// What the status line calls the merged code; `--minify` skips the formatter
+fn merged_kind(args: &Args) -> &'static str {
+    if args.minify {
+        "minified"
+    } else {
+        "formatted"
+    }
+}
+
+fn write_output(output_file: &Path, content: &str) -> Result<()> {
+    if let Some(parent) = output_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(output_file, content).with_context(|| format!("Failed to write {:?}", output_file))
+}
+
 fn determine_package(
     current_dir: &Path,
     package_name: &Option<String>,
@@ -172,12 +1141,12 @@ fn determine_package(
                 .and_then(|m| m.as_array())
                 .context("Failed to get workspace members")?;
 
-            println!("This is a workspace. Available packages:");
+            eprintln!("This is a workspace. Available packages:");
             for (i, member) in members.iter().enumerate() {
-                println!("{}. {}", i + 1, member.as_str().unwrap());
+                eprintln!("{}. {}", i + 1, member.as_str().unwrap());
             }
 
-            println!("Please run the command again with the package name.");
+            eprintln!("Please run the command again with the package name.");
             std::process::exit(1);
         }
 
@@ -191,38 +1160,137 @@ fn determine_package(
 }
 
 fn find_src_dir(package_path: &Path) -> Result<PathBuf> {
-    let cargo_toml = package_path.join("Cargo.toml");
-    let content = fs::read_to_string(cargo_toml)?;
-    let parsed_toml: toml::Value = toml::from_str(&content)?;
+    let src_dir = package_path.join("src");
+    if src_dir.is_dir() {
+        Ok(src_dir)
+    } else {
+        Err(anyhow::anyhow!(
+            "Failed to find src directory in {:?}",
+            package_path
+        ))
+    }
+}
+
+// Directory that file comments are relative to: `src`, unless the target lives elsewhere
+fn target_src_dir(package_path: &Path, target: &Target) -> Result<PathBuf> {
+    match find_src_dir(package_path) {
+        Ok(src_dir) if target.path.starts_with(&src_dir) => Ok(src_dir),
+        _ => target
+            .path
+            .parent()
+            .map(Path::to_path_buf)
+            .context("Failed to get target directory"),
+    }
+}
+
+// Targets merged by `--bins` and `--all-targets`
+fn select_targets(manifest: &Manifest, selection: &TargetSelection) -> Result<Vec<Target>> {
+    let mut targets = Vec::new();
+    if *selection == TargetSelection::AllTargets {
+        targets.extend(manifest.lib_target());
+    }
+    targets.extend(manifest.bin_targets());
+
+    if targets.is_empty() {
+        return Err(anyhow::anyhow!("Package has no targets to merge"));
+    }
+    Ok(targets)
+}
+
+// Pick the target to merge: the one asked for, or the library, or the binary
+fn select_target(
+    manifest: &Manifest,
+    selection: Option<&TargetSelection>,
+    inline_lib: bool,
+) -> Result<Target> {
+    let bins = manifest.bin_targets();
+    let bin_names = || {
+        bins.iter()
+            .map(|bin| bin.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    match selection {
+        Some(TargetSelection::Lib) => manifest
+            .lib_target()
+            .context("Package has no library target"),
+        Some(TargetSelection::Bins | TargetSelection::AllTargets) => Err(anyhow::anyhow!(
+            "Several targets selected, expected a single one"
+        )),
+        Some(TargetSelection::Bin(name)) => bins
+            .iter()
+            .find(|bin| &bin.name == name)
+            .cloned()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No binary target named '{}'; available binaries: {}",
+                    name,
+                    bin_names()
+                )
+            }),
+        None => {
+            // With `--inline-lib` the library ends up inside the binary, so the
+            // binary is merged by default
+            if let Some(lib) = manifest.lib_target().filter(|_| !inline_lib) {
+                return Ok(lib);
+            }
+            let default_bin = bins
+                .iter()
+                .find(|bin| Some(bin.name.as_str()) == manifest.package_name());
+            match (default_bin, bins.as_slice()) {
+                (Some(bin), _) | (None, [bin]) => Ok(bin.clone()),
+                (None, []) => Err(anyhow::anyhow!(
+                    "Package has neither a library nor a binary target"
+                )),
+                (None, _) => Err(anyhow::anyhow!(
+                    "Package has several binaries, pick one with --bin <name>: {}",
+                    bin_names()
+                )),
+            }
+        }
+    }
+}
 
-    parsed_toml
-        .get("package")
-        .and_then(|p| p.get("src"))
-        .and_then(|s| s.as_str())
-        .map(|src| package_path.join(src))
-        .or_else(|| Some(package_path.join("src")))
-        .context("Failed to find src directory")
+fn create_output_file(output_dir: &Path, package_name: &str, format: OutputFormat) -> PathBuf {
+    output_dir
+        .join(package_name)
+        .with_extension(format.extension())
 }
 
-fn create_output_file(output_dir: &Path, package_name: &str) -> PathBuf {
-    output_dir.join(package_name).with_extension("rs")
+// Libraries are written as `lib<name>.rs`, like Cargo's `lib<name>.rlib`, so that
+// they don't clash with the binary of the same name
+fn target_file_name(target: &Target, format: OutputFormat) -> String {
+    match target.kind {
+        TargetKind::Lib => format!("lib{}.{}", target.name, format.extension()),
+        TargetKind::Bin => format!("{}.{}", target.name, format.extension()),
+    }
 }
 
-fn parse_module_structure(src_dir: &Path) -> Result<HashMap<String, ModuleInfo>> {
+fn parse_crate(root_file_path: &Path, src_dir: &Path) -> Result<HashMap<String, ModuleInfo>> {
     let mut module_structure = HashMap::new();
 
-    let root_file_path = if src_dir.join("lib.rs").exists() {
-        src_dir.join("lib.rs")
-    } else if src_dir.join("main.rs").exists() {
-        src_dir.join("main.rs")
-    } else {
-        return Err(anyhow::anyhow!(
-            "Neither lib.rs nor main.rs found in the src directory"
-        ));
-    };
+    parse_file_and_submodules(
+        root_file_path,
+        "crate",
+        &mut module_structure,
+        src_dir,
+        &ModuleDir::crate_root(root_file_path),
+    )?;
 
-    parse_file_and_submodules(&root_file_path, "crate", &mut module_structure, src_dir)?;
+    Ok(module_structure)
+}
 
+// A crate merged in as a module of another, whose root is nowhere near where
+// rustc would look for that module
+fn parse_embedded_crate(
+    root_file_path: &Path,
+    src_dir: &Path,
+) -> Result<HashMap<String, ModuleInfo>> {
+    let mut module_structure = parse_crate(root_file_path, src_dir)?;
+    if let Some(root) = module_structure.get_mut("crate") {
+        root.path_attr = true;
+    }
     Ok(module_structure)
 }
 
@@ -231,108 +1299,54 @@ fn parse_file_and_submodules(
     module_path: &str,
     module_structure: &mut HashMap<String, ModuleInfo>,
     src_dir: &Path,
+    module_dir: &ModuleDir,
 ) -> Result<()> {
-    let content = fs::read_to_string(file_path)?;
+    let content = fs::read_to_string(file_path)
+        .with_context(|| format!("Failed to read module file {:?}", file_path))?;
     let file: File = syn::parse_file(&content)?;
 
-    let mut module_content = TokenStream::new();
+    // File-level inner attributes (`#![allow(...)]`, `//!` docs) open the module
+    let inner_attrs = &file.attrs;
+    let mut module_content = quote! { #(#inner_attrs)* };
+    let mut children = Vec::new();
 
     for item in &file.items {
         if !is_ignored_item(item) {
             match item {
                 Item::Mod(item_mod) => {
                     let submodule_name = &item_mod.ident;
-                    let submodule_path = if module_path == "crate" {
-                        submodule_name.to_string()
-                    } else {
-                        format!("{}::{}", module_path, submodule_name)
-                    };
-
-                    let cfg_attrs = extract_cfg_attrs(&item_mod.attrs);
-
-                    if let Some((_, items)) = &item_mod.content {
-                        // Inline module
-                        let mut submodule_content = TokenStream::new();
-                        for sub_item in items {
-                            sub_item.to_tokens(&mut submodule_content);
-                        }
-                        let expanded = quote! {
-                            #(#cfg_attrs)*
-                            pub mod #submodule_name {
-                                #submodule_content
-                            }
-                        };
-                        expanded.to_tokens(&mut module_content);
-
-                        // Recursively parse nested inline modules
-                        parse_module_items(
-                            items,
-                            file_path,
-                            &submodule_path,
-                            module_structure,
-                            src_dir,
-                        )?;
-                    } else {
-                        // External module file
-                        let parent = file_path
-                            .parent()
-                            .context("Failed to get parent directory")?;
-                        let parent_mod_name = submodule_path.split("::").next().unwrap();
-
-                        let possible_module_files = [
-                            parent.join(submodule_name.to_string()).join("mod.rs"),
-                            parent
-                                .join(parent_mod_name)
-                                .join(format!("{}.rs", submodule_name)),
-                            parent.join(format!("{}.rs", submodule_name)),
-                        ];
-
-                        let submodule_file = possible_module_files
-                            .iter()
-                            .find(|p| p.exists())
-                            .cloned()
-                            .ok_or_else(|| {
-                                anyhow::anyhow!("Failed to find module file for {}", submodule_name)
-                            })?;
-
-                        parse_file_and_submodules(
-                            &submodule_file,
-                            &submodule_path,
-                            module_structure,
-                            src_dir,
-                        )?;
-
-                        // Add the parsed submodule content
-                        if let Some(submodule_info) = module_structure.get(&submodule_path) {
-                            let submodule_content = &submodule_info.content;
-                            let expanded = quote! {
-                                #(#cfg_attrs)*
-                                pub mod #submodule_name {
-                                    #submodule_content
-                                }
-                            };
-                            expanded.to_tokens(&mut module_content);
-                        }
-                    }
+                    let submodule_path = unique_module_key(
+                        module_structure,
+                        &if module_path == "crate" {
+                            submodule_name.to_string()
+                        } else {
+                            format!("{}::{}", module_path, submodule_name)
+                        },
+                    );
+                    children.push(submodule_path.clone());
+
+                    parse_submodule(
+                        item_mod,
+                        &submodule_path,
+                        file_path,
+                        module_structure,
+                        src_dir,
+                        module_dir,
+                        &mut module_content,
+                    )?;
                 }
                 _ => item.to_tokens(&mut module_content),
             }
         }
     }
 
-    // Calculate relative path from src directory
-    let rel_path = file_path
-        .strip_prefix(src_dir)
-        .map(|p| p.to_string_lossy().to_string())
-        .ok();
-
-    module_structure.insert(
-        module_path.to_string(),
-        ModuleInfo {
-            content: module_content,
-            file_path: file_path.to_path_buf(),
-            rel_path,
-        },
+    insert_module(
+        module_structure,
+        module_path,
+        module_content,
+        file_path,
+        src_dir,
+        children,
     );
 
     Ok(())
@@ -344,46 +1358,129 @@ fn parse_module_items(
     module_path: &str,
     module_structure: &mut HashMap<String, ModuleInfo>,
     src_dir: &Path,
+    module_dir: &ModuleDir,
 ) -> Result<()> {
     let mut module_content = TokenStream::new();
+    let mut children = Vec::new();
 
     for item in items {
         if !is_ignored_item(item) {
             match item {
                 Item::Mod(item_mod) => {
-                    let submodule_name = &item_mod.ident;
-                    let submodule_path = format!("{}::{}", module_path, submodule_name);
-
-                    let cfg_attrs = extract_cfg_attrs(&item_mod.attrs);
-
-                    if let Some((_, sub_items)) = &item_mod.content {
-                        let mut submodule_content = TokenStream::new();
-                        for sub_item in sub_items {
-                            sub_item.to_tokens(&mut submodule_content);
-                        }
-                        let expanded = quote! {
-                            #(#cfg_attrs)*
-                            pub mod #submodule_name {
-                                #submodule_content
-                            }
-                        };
-                        expanded.to_tokens(&mut module_content);
-
-                        // Recursively parse nested inline modules
-                        parse_module_items(
-                            sub_items,
-                            file_path,
-                            &submodule_path,
-                            module_structure,
-                            src_dir,
-                        )?;
-                    }
+                    let submodule_path = unique_module_key(
+                        module_structure,
+                        &format!("{}::{}", module_path, item_mod.ident),
+                    );
+                    children.push(submodule_path.clone());
+
+                    parse_submodule(
+                        item_mod,
+                        &submodule_path,
+                        file_path,
+                        module_structure,
+                        src_dir,
+                        module_dir,
+                        &mut module_content,
+                    )?;
                 }
                 _ => item.to_tokens(&mut module_content),
             }
         }
     }
 
+    insert_module(
+        module_structure,
+        module_path,
+        module_content,
+        file_path,
+        src_dir,
+        children,
+    );
+
+    Ok(())
+}
+
+// Parse an inline or external submodule declared in `file_path` and append its
+// expanded form to the parent's content
+fn parse_submodule(
+    item_mod: &ItemMod,
+    submodule_path: &str,
+    file_path: &Path,
+    module_structure: &mut HashMap<String, ModuleInfo>,
+    src_dir: &Path,
+    module_dir: &ModuleDir,
+    module_content: &mut TokenStream,
+) -> Result<()> {
+    let submodule_name = &item_mod.ident;
+    let vis = &item_mod.vis;
+    let attrs = module_attrs(&item_mod.attrs);
+
+    if let Some((_, items)) = &item_mod.content {
+        // Inline module
+        parse_module_items(
+            items,
+            file_path,
+            submodule_path,
+            module_structure,
+            src_dir,
+            &module_dir.inline(submodule_name, &item_mod.attrs),
+        )?;
+
+        // syn keeps the inner attributes of an inline block with its outer ones
+        let inner_attrs = item_mod
+            .attrs
+            .iter()
+            .filter(|attr| matches!(attr.style, AttrStyle::Inner(_)));
+        if let Some(submodule_info) = module_structure.get_mut(submodule_path) {
+            let content = std::mem::take(&mut submodule_info.content);
+            submodule_info.content = quote! {
+                #(#inner_attrs)*
+                #content
+            };
+        }
+    } else {
+        // External module file
+        let resolved = module_dir.resolve(submodule_name, &item_mod.attrs)?;
+
+        parse_file_and_submodules(
+            &resolved.file,
+            submodule_path,
+            module_structure,
+            src_dir,
+            &resolved.dir,
+        )?;
+    }
+
+    // Add the parsed submodule content
+    if let Some(submodule_info) = module_structure.get_mut(submodule_path) {
+        submodule_info.vis = vis.clone();
+        submodule_info.path_attr =
+            item_mod.content.is_none() && path_attr(&item_mod.attrs).is_some();
+        // The `mod` item rebuilt below only has call-site spans
+        let span = item_mod.span();
+        submodule_info.lines = Some((span.start().line, span.end().line));
+
+        let submodule_content = &submodule_info.content;
+        let expanded = quote! {
+            #(#attrs)*
+            #vis mod #submodule_name {
+                #submodule_content
+            }
+        };
+        expanded.to_tokens(module_content);
+    }
+
+    Ok(())
+}
+
+fn insert_module(
+    module_structure: &mut HashMap<String, ModuleInfo>,
+    module_path: &str,
+    content: TokenStream,
+    file_path: &Path,
+    src_dir: &Path,
+    children: Vec<String>,
+) {
     // Calculate relative path from src directory
     let rel_path = file_path
         .strip_prefix(src_dir)
@@ -393,19 +1490,55 @@ fn parse_module_items(
     module_structure.insert(
         module_path.to_string(),
         ModuleInfo {
-            content: module_content,
+            content,
             file_path: file_path.to_path_buf(),
             rel_path,
+            children,
+            vis: Visibility::Inherited,
+            path_attr: false,
+            lines: None,
         },
     );
+}
 
-    Ok(())
+// Pick a key for a module that doesn't collide with an already parsed sibling of
+// the same name, e.g. `#[cfg(unix)] mod imp;` next to `#[cfg(windows)] mod imp;`
+fn unique_module_key(module_structure: &HashMap<String, ModuleInfo>, module_path: &str) -> String {
+    let mut key = module_path.to_string();
+    let mut n = 1;
+    while module_structure.contains_key(&key) {
+        n += 1;
+        key = format!("{}#{}", module_path, n);
+    }
+    key
 }
 
-fn extract_cfg_attrs(attrs: &[Attribute]) -> Vec<&Attribute> {
+// Outer attributes to carry over to a merged module. `#[path]` only matters for
+// locating the module file, so it is dropped once the module is inlined.
+fn module_attrs(attrs: &[Attribute]) -> Vec<Attribute> {
     attrs
         .iter()
-        .filter(|attr| attr.path().is_ident("cfg"))
+        .filter(|attr| matches!(attr.style, AttrStyle::Outer))
+        .filter(|attr| !attr.path().is_ident("path"))
+        .filter_map(|attr| {
+            if !attr.path().is_ident("cfg_attr") {
+                return Some(attr.clone());
+            }
+            let Ok(nested) = attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+            else {
+                return Some(attr.clone());
+            };
+            let mut nested = nested.into_iter();
+            let predicate = nested.next()?;
+            let rest: Vec<Meta> = nested
+                .filter(|meta| !meta.path().is_ident("path"))
+                .collect();
+            if rest.is_empty() {
+                None
+            } else {
+                Some(parse_quote!(#[cfg_attr(#predicate, #(#rest),*)]))
+            }
+        })
         .collect()
 }
 
@@ -464,23 +1597,122 @@ fn is_ignored_item(item: &Item) -> bool {
     false
 }
 
+// The merged crate as tokens, with placeholders for its annotations
+fn merge_tokens(
+    src_dir: &Path,
+    module_structure: &HashMap<String, ModuleInfo>,
+    embedded: &[EmbeddedCrate],
+    items: ItemAnnotations,
+) -> Result<(TokenStream, Annotations)> {
+    let mut annotations = Annotations::new(
+        std::iter::once(module_structure)
+            .chain(embedded.iter().map(|krate| &krate.module_structure))
+            .flat_map(|structure| structure.values())
+            .map(|module_info| module_info.content.to_string()),
+    );
+    annotations.annotate_items(items);
+    let mut merged_content = process_package(src_dir, module_structure, &mut annotations)?;
+    if !embedded.is_empty() {
+        merged_content = embed_crates(merged_content, embedded, &mut annotations)?;
+    }
+    Ok((merged_content, annotations))
+}
+
+fn render_merged(
+    merged_content: &TokenStream,
+    annotations: &Annotations,
+    format_options: &FormatOptions,
+) -> Result<(String, LineMap)> {
+    let formatted_content = format_code(&merged_content.to_string(), format_options)?;
+    Ok(annotations.render(&formatted_content))
+}
+
+// Put each embedded crate into a `mod` at the top of the merged crate, and point
+// the paths of all the crates at their new locations
+fn embed_crates(
+    merged_content: TokenStream,
+    embedded: &[EmbeddedCrate],
+    annotations: &mut Annotations,
+) -> Result<TokenStream> {
+    let names: HashMap<String, String> = embedded
+        .iter()
+        .flat_map(|krate| {
+            std::iter::once(&krate.name)
+                .chain(&krate.aliases)
+                .map(|name| (name.clone(), krate.name.clone()))
+        })
+        .collect();
+    let exported_macros: HashSet<String> = embedded
+        .iter()
+        .flat_map(|krate| {
+            rewrite::exported_macros(
+                krate
+                    .module_structure
+                    .values()
+                    .map(|module_info| &module_info.content),
+            )
+        })
+        .collect();
+    let paths = CratePaths::new(&names, &exported_macros);
+
+    let mut modules = TokenStream::new();
+    for krate in embedded {
+        let content = process_package(&krate.src_dir, &krate.module_structure, annotations)?;
+        let content = paths.rewrite(content, Some(&krate.name));
+        let ident = format_ident!("{}", krate.name);
+        let generated = annotations.marker(Annotation::generated());
+        // `#[macro_use]` keeps the crate's macros in scope by name, as with
+        // `#[macro_use] extern crate`
+        quote! {
+            #generated
+            #[macro_use]
+            pub mod #ident {
+                #content
+            }
+        }
+        .to_tokens(&mut modules);
+    }
+
+    let file: File =
+        syn::parse2(paths.rewrite(merged_content, None)).context("Failed to parse merged code")?;
+    let attrs = file.attrs;
+    let mut items = file.items.into_iter();
+    // The merged crate opens with the comment for its root file, whose code
+    // picks up again after the embedded crates
+    let root_comment = items.next();
+    let resumed = root_comment
+        .as_ref()
+        .and_then(|item| {
+            let tokens: Vec<TokenTree> = item.to_token_stream().into_iter().collect();
+            annotations.marked(&tokens).cloned()
+        })
+        .map(|annotation| {
+            annotations.marker(Annotation {
+                silent: true,
+                ..annotation
+            })
+        });
+    Ok(quote! {
+        #(#attrs)*
+        #root_comment
+        #modules
+        #resumed
+        #(#items)*
+    })
+}
+
 fn process_package(
     src_dir: &Path,
     module_structure: &HashMap<String, ModuleInfo>,
+    annotations: &mut Annotations,
 ) -> Result<TokenStream> {
     let mut merged_content = TokenStream::new();
 
-    let root_module = if src_dir.join("lib.rs").exists() {
-        "crate"
-    } else if src_dir.join("main.rs").exists() {
-        "crate"
-    } else {
-        return Err(anyhow::anyhow!(
-            "Neither lib.rs nor main.rs found in the src directory"
-        ));
-    };
+    if !module_structure.contains_key("crate") {
+        return Err(anyhow::anyhow!("No crate root found in {:?}", src_dir));
+    }
 
-    process_module(root_module, module_structure, &mut merged_content)?;
+    process_module("crate", module_structure, &mut merged_content, annotations)?;
 
     Ok(merged_content)
 }
@@ -489,11 +1721,26 @@ fn process_module(
     module_path: &str,
     module_structure: &HashMap<String, ModuleInfo>,
     output: &mut TokenStream,
+    annotations: &mut Annotations,
+) -> Result<()> {
+    // The root module has no parent file, so it always gets a file comment
+    process_module_content(module_path, module_structure, output, annotations, "")
+}
+
+// Process module content with tracking of parent file path
+fn process_module_content(
+    module_path: &str,
+    module_structure: &HashMap<String, ModuleInfo>,
+    output: &mut TokenStream,
+    annotations: &mut Annotations,
+    parent_file_path: &str, // Track parent file path to avoid duplicate comments
 ) -> Result<()> {
     if let Some(module_info) = module_structure.get(module_path) {
-        let file = syn::parse_file(&module_info.content.to_string())?;
+        // Parsed from the tokens themselves, so that they keep their source locations
+        let file: File = syn::parse2(module_info.content.clone())?;
+        let mut children = module_info.children.iter();
 
-        // Get relative file path for comment for the root module
+        // Get relative file path for comment
         let file_path_str = module_info.rel_path.as_deref().unwrap_or_else(|| {
             module_info
                 .file_path
@@ -502,149 +1749,91 @@ fn process_module(
                 .unwrap_or("unknown.rs")
         });
 
-        // Add root module file comment - encode filename to avoid issues with special characters
-        let marker = format!("RUSTMERGE_COMMENT_{}", encode_filename(file_path_str));
-        let marker_lit = proc_macro2::Literal::string(&marker);
-        let comment_tokens = quote! {
-            const _: &'static str = #marker_lit;
-        };
-        comment_tokens.to_tokens(output);
-
-        for item in file.items {
-            if !is_ignored_item(&item) {
-                match item {
-                    Item::Mod(ItemMod { ident, content, .. }) => {
-                        let submodule_path = if module_path == "crate" {
-                            ident.to_string()
-                        } else {
-                            format!("{}::{}", module_path, ident)
-                        };
-
-                        let mut submodule_content = TokenStream::new();
-
-                        // Find the actual file path for this module
-                        if let Some(submodule_info) = module_structure.get(&submodule_path) {
-                            // Get this module's file path
-                            let sub_path_str =
-                                submodule_info.rel_path.as_deref().unwrap_or_else(|| {
-                                    submodule_info
-                                        .file_path
-                                        .file_name()
-                                        .and_then(|name| name.to_str())
-                                        .unwrap_or("unknown.rs")
-                                });
-
-                            // Only add comment if the module is in a different file than its parent
-                            if sub_path_str != file_path_str {
-                                let sub_marker =
-                                    format!("RUSTMERGE_COMMENT_{}", encode_filename(sub_path_str));
-                                let sub_marker_lit = proc_macro2::Literal::string(&sub_marker);
-
-                                let sub_comment_tokens = quote! {
-                                    const _: &'static str = #sub_marker_lit;
-                                };
-                                sub_comment_tokens.to_tokens(&mut submodule_content);
-                            }
-
-                            // Process the content of the module
-                            process_module_content(
-                                &submodule_path,
-                                module_structure,
-                                &mut submodule_content,
-                                sub_path_str, // Pass the current module's file path
-                            )?;
-                        }
-
-                        let expanded = if submodule_content.is_empty() && content.is_none() {
-                            quote! {
-                                pub mod #ident;
-                            }
-                        } else {
-                            quote! {
-                                pub mod #ident {
-                                    #submodule_content
-                                }
-                            }
-                        };
-                        expanded.to_tokens(output);
-                    }
-                    _ => item.to_tokens(output),
-                }
-            }
+        // Inner attributes must precede everything else in the module
+        for attr in &file.attrs {
+            attr.to_tokens(output);
         }
-    }
 
-    Ok(())
-}
-
-// Process module content with tracking of parent file path
-fn process_module_content(
-    module_path: &str,
-    module_structure: &HashMap<String, ModuleInfo>,
-    output: &mut TokenStream,
-    parent_file_path: &str, // Track parent file path to avoid duplicate comments
-) -> Result<()> {
-    if let Some(module_info) = module_structure.get(module_path) {
-        let file = syn::parse_file(&module_info.content.to_string())?;
+        // Only add comment if module is in a different file than its parent
+        if file_path_str != parent_file_path {
+            annotations
+                .marker(Annotation {
+                    path_attr: module_info.path_attr,
+                    ..Annotation::file(file_path_str, &module_info.file_path)
+                })
+                .to_tokens(output);
+        }
 
         for item in file.items {
             if !is_ignored_item(&item) {
+                let submodule_path = matches!(item, Item::Mod(_))
+                    .then(|| children.next().cloned().unwrap_or_default());
+                // `mod` items were rebuilt while parsing, so their lines come
+                // from the module rather than from their spans
+                let lines = match &submodule_path {
+                    Some(submodule_path) => module_structure
+                        .get(submodule_path)
+                        .and_then(|submodule_info| submodule_info.lines)
+                        .unwrap_or_default(),
+                    None => (item.span().start().line, item.span().end().line),
+                };
+                // rustfmt sorts runs of `use` items, which a placeholder would break up
+                let item_marker = if lines.0 > 0 && !matches!(item, Item::Use(_)) {
+                    annotations.item_marker(file_path_str, &module_info.file_path, lines)
+                } else {
+                    TokenStream::new()
+                };
                 match item {
-                    Item::Mod(ItemMod { ident, content, .. }) => {
-                        let submodule_path = if module_path == "crate" {
-                            ident.to_string()
-                        } else {
-                            format!("{}::{}", module_path, ident)
-                        };
+                    Item::Mod(ItemMod {
+                        attrs,
+                        vis,
+                        ident,
+                        content,
+                        ..
+                    }) => {
+                        let submodule_path = submodule_path.unwrap_or_default();
 
                         let mut submodule_content = TokenStream::new();
+                        let mut vis = vis;
 
-                        // Add file comment if this module is in a different file
                         if let Some(submodule_info) = module_structure.get(&submodule_path) {
-                            let sub_path_str =
-                                submodule_info.rel_path.as_deref().unwrap_or_else(|| {
-                                    submodule_info
-                                        .file_path
-                                        .file_name()
-                                        .and_then(|name| name.to_str())
-                                        .unwrap_or("unknown.rs")
-                                });
-
-                            // Only add comment if module is in a different file than its parent
-                            if sub_path_str != parent_file_path {
-                                let sub_marker =
-                                    format!("RUSTMERGE_COMMENT_{}", encode_filename(sub_path_str));
-                                let sub_marker_lit = proc_macro2::Literal::string(&sub_marker);
-
-                                let sub_comment_tokens = quote! {
-                                    const _: &'static str = #sub_marker_lit;
-                                };
-                                sub_comment_tokens.to_tokens(&mut submodule_content);
-                            }
+                            vis = submodule_info.vis.clone();
 
                             // Process this module's content
                             process_module_content(
                                 &submodule_path,
                                 module_structure,
                                 &mut submodule_content,
-                                sub_path_str, // Pass this module's file path
+                                annotations,
+                                file_path_str, // Pass this module's file path
                             )?;
                         }
 
+                        // Inner attributes were emitted inside the block above
+                        let attrs = attrs
+                            .iter()
+                            .filter(|attr| matches!(attr.style, AttrStyle::Outer));
+
                         let expanded = if submodule_content.is_empty() && content.is_none() {
                             quote! {
-                                pub mod #ident;
+                                #(#attrs)*
+                                #vis mod #ident;
                             }
                         } else {
                             quote! {
-                                pub mod #ident {
+                                #item_marker
+                                #(#attrs)*
+                                #vis mod #ident {
                                     #submodule_content
                                 }
                             }
                         };
                         expanded.to_tokens(output);
                     }
-                    _ => item.to_tokens(output),
+                    _ => {
+                        item_marker.to_tokens(output);
+                        item.to_tokens(output);
+                    }
                 }
             }
         }
@@ -653,30 +1842,35 @@ fn process_module_content(
     Ok(())
 }
 
-// Helper function to safely encode filenames for our markers
-fn encode_filename(filename: &str) -> String {
-    // Use base64 encoding to safely handle any special characters
-    use std::fmt::Write;
-
-    let mut encoded = String::new();
-    for byte in filename.bytes() {
-        match byte {
-            b'.' => write!(encoded, "__DOT__").unwrap(),
-            b'_' => write!(encoded, "__UNDERSCORE__").unwrap(),
-            b'/' => write!(encoded, "__SLASH__").unwrap(),
-            b'-' => write!(encoded, "__DASH__").unwrap(),
-            _ => encoded.push(byte as char),
-        }
+fn format_code(code: &str, format_options: &FormatOptions) -> Result<String> {
+    match format_options.formatter {
+        Formatter::Auto => format_rust_code(code, format_options).or_else(|err| {
+            eprintln!("Warning: {:#}; falling back to the built-in formatter", err);
+            format_builtin(code)
+        }),
+        Formatter::Rustfmt => format_rust_code(code, format_options),
+        Formatter::Builtin => format_builtin(code),
+        Formatter::None => Ok(code.to_string()),
     }
-    encoded
 }
 
-fn format_rust_code(code: &str) -> Result<String> {
-    // Run rustfmt first to get well-formatted code
-    let mut rustfmt = Command::new("rustfmt")
-        .arg("--edition=2021")
+// In-process pretty printer, for machines without rustfmt
+fn format_builtin(code: &str) -> Result<String> {
+    let file = syn::parse_file(code).context("Failed to parse merged code for formatting")?;
+    Ok(prettyplease::unparse(&file))
+}
+
+fn format_rust_code(code: &str, format_options: &FormatOptions) -> Result<String> {
+    let mut rustfmt = Command::new("rustfmt");
+    rustfmt.arg(format!("--edition={}", format_options.edition));
+    if let Some(config_path) = &format_options.rustfmt_config {
+        rustfmt.arg("--config-path").arg(config_path);
+    }
+
+    let mut rustfmt = rustfmt
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
         .context("Failed to spawn rustfmt")?;
 
@@ -698,27 +1892,20 @@ fn format_rust_code(code: &str) -> Result<String> {
         ));
     }
 
-    let formatted =
-        String::from_utf8(output.stdout).context("rustfmt output was not valid UTF-8")?;
-
-    // Now replace our markers with actual comments
-    let pattern = r#"const\s+_\s*:\s*&\s*'static\s*str\s*=\s*"RUSTMERGE_COMMENT_([^"]+)"\s*;"#;
-    let re = Regex::new(pattern).unwrap();
-    let result = re.replace_all(&formatted, |caps: &regex::Captures| {
-        let encoded_filename = &caps[1];
-        let filename = decode_filename(encoded_filename);
-        format!("// {}", filename)
-    });
-
-    Ok(result.to_string())
+    String::from_utf8(output.stdout).context("rustfmt output was not valid UTF-8")
 }
 
-// Helper function to decode our specially encoded filenames
-fn decode_filename(encoded: &str) -> String {
-    let mut result = encoded.to_string();
-    result = result.replace("__DOT__", ".");
-    result = result.replace("__UNDERSCORE__", "_");
-    result = result.replace("__SLASH__", "/");
-    result = result.replace("__DASH__", "-");
-    result
+// rustfmt looks for its config in the directory of the formatted file and its
+// parents; the merged code comes in on stdin, so search from the package instead
+fn find_rustfmt_config(package_path: &Path) -> Option<PathBuf> {
+    let package_path = package_path
+        .canonicalize()
+        .unwrap_or_else(|_| package_path.to_path_buf());
+
+    package_path.ancestors().find_map(|dir| {
+        ["rustfmt.toml", ".rustfmt.toml"]
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|config| config.is_file())
+    })
 }
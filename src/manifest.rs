@@ -0,0 +1,333 @@
+// The parts of a package's Cargo.toml that rustmerge needs: package fields (with
+// `workspace = true` resolved against the enclosing workspace), the library and
+// binary targets as Cargo discovers them, and the path dependencies.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetKind {
+    Lib,
+    Bin,
+}
+
+impl fmt::Display for TargetKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetKind::Lib => write!(f, "lib"),
+            TargetKind::Bin => write!(f, "bin"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub kind: TargetKind,
+    pub name: String,
+    pub path: PathBuf, // Crate root file
+}
+
+// A `path = "..."` entry of `[dependencies]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathDependency {
+    pub package_dir: PathBuf,
+    pub rename: Option<String>, // Name the crate goes by when renamed with `package = "..."`
+}
+
+#[derive(Debug)]
+pub struct Manifest {
+    package_dir: PathBuf,
+    toml: toml::Value,
+    workspace: Option<(PathBuf, toml::Value)>, // Root directory and manifest of the enclosing workspace
+}
+
+impl Manifest {
+    pub fn load(package_dir: &Path) -> Result<Self> {
+        let toml = read_toml(&package_dir.join("Cargo.toml"))?;
+        let workspace = if toml.get("workspace").is_some() {
+            Some((package_dir.to_path_buf(), toml.clone()))
+        } else {
+            find_workspace_manifest(package_dir)
+        };
+
+        Ok(Manifest {
+            package_dir: package_dir.to_path_buf(),
+            toml,
+            workspace,
+        })
+    }
+
+    // `package.<key>`, following `<key>.workspace = true` to `[workspace.package]`
+    pub fn package_field(&self, key: &str) -> Option<&toml::Value> {
+        let value = self.toml.get("package")?.get(key)?;
+        if value.get("workspace").and_then(|w| w.as_bool()) == Some(true) {
+            return self
+                .workspace
+                .as_ref()?
+                .1
+                .get("workspace")?
+                .get("package")?
+                .get(key);
+        }
+        Some(value)
+    }
+
+    pub fn package_name(&self) -> Option<&str> {
+        self.toml.get("package")?.get("name")?.as_str()
+    }
+
+    // `[lib]`, or `src/lib.rs` when the section is left out
+    pub fn lib_target(&self) -> Option<Target> {
+        let lib = self.toml.get("lib");
+        let path = match lib.and_then(|lib| lib.get("path")).and_then(|p| p.as_str()) {
+            Some(path) => self.package_dir.join(path),
+            None => self.package_dir.join("src").join("lib.rs"),
+        };
+        if !path.is_file() {
+            return None;
+        }
+
+        let name = lib
+            .and_then(|lib| lib.get("name"))
+            .and_then(|name| name.as_str())
+            .or_else(|| self.package_name())
+            .unwrap_or("lib")
+            .replace('-', "_");
+        Some(Target {
+            kind: TargetKind::Lib,
+            name,
+            path,
+        })
+    }
+
+    // `[[bin]]` entries plus, unless `autobins = false`, the binaries Cargo discovers
+    // on its own: `src/main.rs`, `src/bin/*.rs` and `src/bin/*/main.rs`
+    pub fn bin_targets(&self) -> Vec<Target> {
+        let package_name = self.package_name().unwrap_or_default();
+        let mut bins: Vec<Target> = Vec::new();
+
+        for bin in self
+            .toml
+            .get("bin")
+            .and_then(|bins| bins.as_array())
+            .into_iter()
+            .flatten()
+        {
+            let Some(name) = bin.get("name").and_then(|name| name.as_str()) else {
+                continue;
+            };
+            let path = match bin.get("path").and_then(|path| path.as_str()) {
+                Some(path) => Some(self.package_dir.join(path)),
+                None => self.infer_bin_path(name, name == package_name),
+            };
+            if let Some(path) = path {
+                bins.push(Target {
+                    kind: TargetKind::Bin,
+                    name: name.to_string(),
+                    path,
+                });
+            }
+        }
+
+        let autobins = self
+            .package_field("autobins")
+            .and_then(|autobins| autobins.as_bool())
+            .unwrap_or(true);
+        if autobins {
+            for target in self.discover_bins(package_name) {
+                // Explicit targets win over discovered ones with the same name or file
+                if !bins
+                    .iter()
+                    .any(|bin| bin.name == target.name || bin.path == target.path)
+                {
+                    bins.push(target);
+                }
+            }
+        }
+
+        bins.sort_by(|a, b| a.name.cmp(&b.name));
+        bins
+    }
+
+    fn infer_bin_path(&self, name: &str, is_package_name: bool) -> Option<PathBuf> {
+        let bin_dir = self.package_dir.join("src").join("bin");
+        let mut candidates = vec![
+            bin_dir.join(format!("{}.rs", name)),
+            bin_dir.join(name).join("main.rs"),
+        ];
+        if is_package_name {
+            candidates.push(self.package_dir.join("src").join("main.rs"));
+        }
+        candidates.into_iter().find(|path| path.is_file())
+    }
+
+    fn discover_bins(&self, package_name: &str) -> Vec<Target> {
+        let mut bins = Vec::new();
+
+        let main_rs = self.package_dir.join("src").join("main.rs");
+        if main_rs.is_file() {
+            bins.push(Target {
+                kind: TargetKind::Bin,
+                name: package_name.to_string(),
+                path: main_rs,
+            });
+        }
+
+        let Ok(entries) = fs::read_dir(self.package_dir.join("src").join("bin")) else {
+            return bins;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let (name, path) = if path.is_dir() {
+                (entry.file_name(), path.join("main.rs"))
+            } else if path.extension().is_some_and(|ext| ext == "rs") {
+                (path.file_stem().unwrap_or_default().to_owned(), path)
+            } else {
+                continue;
+            };
+            if path.is_file() {
+                bins.push(Target {
+                    kind: TargetKind::Bin,
+                    name: name.to_string_lossy().to_string(),
+                    path,
+                });
+            }
+        }
+        bins
+    }
+
+    // Non-optional `[dependencies]` that live on the local file system, including
+    // `dep.workspace = true` entries pointing at such a dependency
+    pub fn path_dependencies(&self) -> Vec<PathDependency> {
+        let mut path_dependencies = Vec::new();
+        for (key, dependency) in self.dependencies() {
+            if dependency.get("optional").and_then(|o| o.as_bool()) == Some(true) {
+                continue;
+            }
+            let Some(path) = dependency.get("path").and_then(|p| p.as_str()) else {
+                continue;
+            };
+            path_dependencies.push(PathDependency {
+                package_dir: PathBuf::from(path),
+                rename: dependency
+                    .get("package")
+                    .is_some()
+                    .then(|| key.replace('-', "_")),
+            });
+        }
+        path_dependencies
+    }
+
+    // `[dependencies]` as another manifest would have to spell them: `workspace = true`
+    // entries are filled in from the workspace, and paths are made absolute.
+    // Entries the workspace doesn't define are left out.
+    pub fn dependencies(&self) -> Vec<(String, toml::Table)> {
+        let Some(dependencies) = self.toml.get("dependencies").and_then(|d| d.as_table()) else {
+            return Vec::new();
+        };
+
+        let mut resolved = Vec::new();
+        for (key, dependency) in dependencies {
+            let mut table = as_dependency_table(dependency);
+            let mut base_dir = self.package_dir.as_path();
+            if table.remove("workspace").and_then(|w| w.as_bool()) == Some(true) {
+                let Some((workspace_dir, workspace)) = &self.workspace else {
+                    continue;
+                };
+                let Some(source) = workspace
+                    .get("workspace")
+                    .and_then(|w| w.get("dependencies"))
+                    .and_then(|d| d.get(key))
+                else {
+                    continue;
+                };
+                // The member can add features and make the dependency optional
+                let mut inherited = as_dependency_table(source);
+                if let Some(toml::Value::Array(features)) = table.remove("features") {
+                    let all = inherited
+                        .entry("features")
+                        .or_insert_with(|| toml::Value::Array(Vec::new()));
+                    if let toml::Value::Array(all) = all {
+                        all.extend(features);
+                    }
+                }
+                inherited.extend(table);
+                table = inherited;
+                base_dir = workspace_dir;
+            }
+
+            if let Some(path) = table.get("path").and_then(|p| p.as_str()) {
+                let path = absolute(&base_dir.join(path));
+                table.insert(
+                    "path".to_string(),
+                    toml::Value::String(path.to_string_lossy().to_string()),
+                );
+            }
+            resolved.push((key.clone(), table));
+        }
+        resolved
+    }
+
+    // A `[dependencies]` entry for this package that works from any directory
+    pub fn path_dependency_on_self(&self) -> toml::Table {
+        let path = absolute(&self.package_dir);
+        toml::Table::from_iter([(
+            "path".to_string(),
+            toml::Value::String(path.to_string_lossy().to_string()),
+        )])
+    }
+
+    // The lockfile the package builds with: its workspace's, or its own
+    pub fn lockfile(&self) -> Option<PathBuf> {
+        self.workspace
+            .iter()
+            .map(|(root, _)| root.join("Cargo.lock"))
+            .chain([self.package_dir.join("Cargo.lock")])
+            .find(|lockfile| lockfile.is_file())
+    }
+
+    pub fn features(&self) -> Option<&toml::Table> {
+        self.toml.get("features")?.as_table()
+    }
+
+    // Cargo defaults to the 2015 edition when none is given
+    pub fn edition(&self) -> String {
+        self.package_field("edition")
+            .and_then(|edition| edition.as_str())
+            .unwrap_or("2015")
+            .to_string()
+    }
+}
+
+// `dep = "1.0"` is short for `dep = { version = "1.0" }`
+fn as_dependency_table(dependency: &toml::Value) -> toml::Table {
+    match dependency {
+        toml::Value::Table(table) => table.clone(),
+        version => toml::Table::from_iter([("version".to_string(), version.clone())]),
+    }
+}
+
+pub fn absolute(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn read_toml(path: &Path) -> Result<toml::Value> {
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    toml::from_str(&content).with_context(|| format!("Failed to parse {:?}", path))
+}
+
+fn find_workspace_manifest(package_dir: &Path) -> Option<(PathBuf, toml::Value)> {
+    let package_dir = package_dir
+        .canonicalize()
+        .unwrap_or_else(|_| package_dir.to_path_buf());
+
+    package_dir
+        .ancestors()
+        .skip(1)
+        .filter(|dir| dir.join("Cargo.toml").is_file())
+        .filter_map(|dir| Some((dir.to_path_buf(), read_toml(&dir.join("Cargo.toml")).ok()?)))
+        .find(|(_, toml)| toml.get("workspace").is_some())
+}
@@ -0,0 +1,86 @@
+// `--format markdown`: the merged target as a document to paste into a chat or a
+// wiki. A header names the package, a tree shows how the module files nest, and
+// each file follows under its own heading, in module order. The files are the
+// merge split back up at its file comments, so they read the way the merge left
+// them: formatted, shaken, or as they are with `--preserve-comments`.
+
+use std::fmt::Write;
+
+use crate::unmerge::SourceFile;
+
+pub struct Section<'a> {
+    pub heading: String, // The file's path, as the reader knows it
+    pub file: &'a SourceFile,
+}
+
+pub fn document(title: &str, summary: &str, sections: &[Section]) -> String {
+    let mut markdown = String::new();
+    writeln!(markdown, "# {}\n\n{}\n", title, summary).unwrap();
+
+    writeln!(markdown, "## Modules\n\n```text").unwrap();
+    if let Some((root, modules)) = sections.split_first() {
+        writeln!(markdown, "crate ({})", root.heading).unwrap();
+        write_tree(&mut markdown, modules, &root.file.module, "");
+    }
+    writeln!(markdown, "```\n\n## Files").unwrap();
+
+    for section in sections {
+        let text = &section.file.text;
+        let fence = fence(text);
+        // The closing fence has to start a line of its own
+        let newline = if text.ends_with('\n') { "" } else { "\n" };
+        write!(
+            markdown,
+            "\n### {}\n\n{}rust\n{}{}{}\n",
+            section.heading, fence, text, newline, fence
+        )
+        .unwrap();
+    }
+    markdown
+}
+
+// The modules declared in `parent`, each with those declared in its own file,
+// which come right after it
+fn write_tree(markdown: &mut String, sections: &[Section], parent: &str, indent: &str) {
+    let prefix = format!("{}::", parent);
+    let mut rest = sections;
+    while let Some((section, after)) = rest.split_first() {
+        let module = &section.file.module;
+        let nested = after
+            .iter()
+            .take_while(|other| other.file.module.starts_with(&format!("{}::", module)))
+            .count();
+        let last = nested == after.len();
+        let (branch, continuation) = if last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        writeln!(
+            markdown,
+            "{}{}{} ({})",
+            indent,
+            branch,
+            module.strip_prefix(&prefix).unwrap_or(module),
+            section.heading
+        )
+        .unwrap();
+        write_tree(
+            markdown,
+            &after[..nested],
+            module,
+            &format!("{}{}", indent, continuation),
+        );
+        rest = &after[nested..];
+    }
+}
+
+// A code fence longer than any that starts a line of `text`, which would close it
+fn fence(text: &str) -> String {
+    let mut longest = 0;
+    for line in text.lines() {
+        let backticks = line.trim_start().chars().take_while(|&c| c == '`').count();
+        longest = longest.max(backticks);
+    }
+    "`".repeat(longest.max(2) + 1)
+}
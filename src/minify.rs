@@ -0,0 +1,476 @@
+// Minified output, for judges and script hosts with a size limit.
+//
+// The merged tokens are printed back with only the whitespace that keeps two
+// neighbouring tokens from running into each other, and without doc comments or
+// file comments. Optionally the local variables of each function are renamed to
+// the shortest names that occur nowhere in the crate. Only names that are bound
+// and used in plain code are renamed: a name that shows up in the tokens of a
+// macro the renamer can't see through, in a format string, or as the name of an
+// item is left alone.
+
+use std::collections::{HashMap, HashSet};
+
+use proc_macro2::{Delimiter, Group, Spacing, TokenStream, TokenTree};
+use quote::ToTokens;
+use regex::Regex;
+use syn::punctuated::Punctuated;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    Block, Expr, ExprPath, FieldPat, FieldValue, File, FnArg, Ident, Item, Macro, Member, PatIdent,
+    Signature, Token, UseTree,
+};
+
+use crate::annotate::Annotations;
+use crate::treeshake::collect_idents;
+
+// Standard macros whose arguments are plain comma-separated expressions
+const EXPRESSION_MACROS: &[&str] = &[
+    "assert",
+    "assert_eq",
+    "assert_ne",
+    "dbg",
+    "debug_assert",
+    "debug_assert_eq",
+    "debug_assert_ne",
+    "eprint",
+    "eprintln",
+    "format",
+    "format_args",
+    "panic",
+    "print",
+    "println",
+    "todo",
+    "unimplemented",
+    "unreachable",
+    "vec",
+    "write",
+    "writeln",
+];
+
+// Pairs of characters that would lex as one token, or start a comment, if two
+// punctuation tokens were printed next to each other
+const JOINING_PAIRS: &[&str] = &[
+    "::", "->", "=>", "==", "!=", "<=", ">=", "&&", "||", "+=", "-=", "*=", "/=", "%=", "^=", "&=",
+    "|=", "<<", ">>", "..", ".=", "<-", "//", "/*", "*/",
+];
+
+pub fn minify(
+    content: &TokenStream,
+    annotations: &Annotations,
+    shorten_idents: bool,
+) -> syn::Result<String> {
+    let content = if shorten_idents {
+        let mut file: File = syn::parse2(content.clone())?;
+        shorten_locals(&mut file);
+        file.into_token_stream()
+    } else {
+        content.clone()
+    };
+
+    let mut printer = Printer {
+        annotations,
+        output: String::new(),
+        last: Last::Delimiter,
+    };
+    printer.print(content);
+    printer.output.push('\n');
+    Ok(printer.output)
+}
+
+#[derive(Clone, Copy)]
+enum Last {
+    Ident,
+    Literal,
+    Punct(char, Spacing),
+    Delimiter,
+}
+
+struct Printer<'a> {
+    annotations: &'a Annotations,
+    output: String,
+    last: Last, // What the output ends with
+}
+
+impl Printer<'_> {
+    fn print(&mut self, tokens: TokenStream) {
+        let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+        let mut i = 0;
+        while i < tokens.len() {
+            if let Some(skipped) = self.skipped(&tokens[i..]) {
+                i += skipped;
+                continue;
+            }
+            match &tokens[i] {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.push(open, Last::Delimiter);
+                    self.print(group.stream());
+                    self.push(close, Last::Delimiter);
+                }
+                TokenTree::Ident(ident) => {
+                    self.separate(matches!(self.last, Last::Ident | Last::Literal));
+                    self.push(&ident.to_string(), Last::Ident);
+                }
+                TokenTree::Literal(literal) => {
+                    self.separate(matches!(self.last, Last::Ident | Last::Literal));
+                    self.push(&literal.to_string(), Last::Literal);
+                }
+                TokenTree::Punct(punct) => {
+                    let ch = punct.as_char();
+                    self.separate(match self.last {
+                        // `break 'a`, and `r #x` or `k #x` that are no raw identifiers
+                        Last::Ident => ch == '\'' || ch == '#',
+                        // `1 .max(2)` and `1 ..2` must not lex as a float
+                        Last::Literal => matches!(ch, '.' | '\'' | '#'),
+                        Last::Punct(prev, Spacing::Alone) => {
+                            JOINING_PAIRS.contains(&format!("{}{}", prev, ch).as_str())
+                        }
+                        Last::Punct(_, Spacing::Joint) | Last::Delimiter => false,
+                    });
+                    self.push(&ch.to_string(), Last::Punct(ch, punct.spacing()));
+                }
+            }
+            i += 1;
+        }
+    }
+
+    // Doc comments and file comment placeholders are left out
+    fn skipped(&self, tokens: &[TokenTree]) -> Option<usize> {
+        match tokens {
+            _ if self.annotations.marked(tokens).is_some() => Some(4),
+            [TokenTree::Punct(pound), TokenTree::Group(group), ..]
+                if pound.as_char() == '#' && is_doc_attribute(group) =>
+            {
+                Some(2)
+            }
+            [TokenTree::Punct(pound), TokenTree::Punct(bang), TokenTree::Group(group), ..]
+                if pound.as_char() == '#' && bang.as_char() == '!' && is_doc_attribute(group) =>
+            {
+                Some(3)
+            }
+            _ => None,
+        }
+    }
+
+    fn separate(&mut self, needs_space: bool) {
+        if needs_space {
+            self.output.push(' ');
+        }
+    }
+
+    fn push(&mut self, text: &str, last: Last) {
+        if !text.is_empty() {
+            self.output.push_str(text);
+            self.last = last;
+        }
+    }
+}
+
+// `[doc = "..."]`, but not `[doc(hidden)]` or a `[doc = $doc]` in a macro
+pub fn is_doc_attribute(group: &Group) -> bool {
+    let tokens: Vec<TokenTree> = group.stream().into_iter().collect();
+    group.delimiter() == Delimiter::Bracket
+        && matches!(
+            tokens.as_slice(),
+            [TokenTree::Ident(doc), TokenTree::Punct(eq), TokenTree::Literal(_)]
+                if doc == "doc" && eq.as_char() == '='
+        )
+}
+
+fn shorten_locals(file: &mut File) {
+    let mut idents = Vec::new();
+    collect_idents(file.to_token_stream(), &mut idents);
+    let mut item_names = ItemNames::default();
+    item_names.visit_file(file);
+
+    Functions {
+        taken: idents.into_iter().collect(),
+        item_names: item_names.names,
+    }
+    .visit_file_mut(file);
+}
+
+// Every function in the crate, nested ones included, gets its own renaming
+struct Functions {
+    taken: HashSet<String>,      // Every identifier in the crate
+    item_names: HashSet<String>, // Names of items, variants and imports
+}
+
+impl Functions {
+    fn shorten(&self, sig: &mut Signature, block: &mut Block) {
+        let mut bindings = Bindings::default();
+        for input in &sig.inputs {
+            if let FnArg::Typed(pat_type) = input {
+                bindings.visit_pat(&pat_type.pat);
+            }
+        }
+        bindings.visit_block(block);
+
+        let mut idents = Vec::new();
+        collect_idents(block.to_token_stream(), &mut idents);
+        let mut uses: HashMap<String, usize> = HashMap::new();
+        for ident in idents {
+            *uses.entry(ident).or_default() += 1;
+        }
+
+        let mut names: Vec<String> = bindings
+            .names
+            .into_iter()
+            .filter(|name| {
+                !bindings.pinned.contains(name)
+                    && !self.item_names.contains(name)
+                    && name.starts_with(|ch: char| ch.is_ascii_lowercase() || ch == '_')
+            })
+            .collect();
+        // The most used names get the shortest replacements
+        names.sort_by(|a, b| uses.get(b).cmp(&uses.get(a)).then(a.cmp(b)));
+
+        let mut renames = HashMap::new();
+        let mut next = 0;
+        for name in names {
+            let short = loop {
+                let short = short_name(next);
+                if !self.taken.contains(&short) && syn::parse_str::<Ident>(&short).is_ok() {
+                    break short;
+                }
+                next += 1;
+            };
+            if short.len() < name.len() {
+                renames.insert(name, short);
+                next += 1;
+            }
+        }
+        if renames.is_empty() {
+            return;
+        }
+
+        let mut renamer = Renamer { renames };
+        for input in &mut sig.inputs {
+            if let FnArg::Typed(pat_type) = input {
+                renamer.visit_pat_mut(&mut pat_type.pat);
+            }
+        }
+        renamer.visit_block_mut(block);
+    }
+}
+
+impl VisitMut for Functions {
+    fn visit_item_fn_mut(&mut self, item_fn: &mut syn::ItemFn) {
+        self.shorten(&mut item_fn.sig, &mut item_fn.block);
+        visit_mut::visit_item_fn_mut(self, item_fn);
+    }
+
+    fn visit_impl_item_fn_mut(&mut self, item_fn: &mut syn::ImplItemFn) {
+        self.shorten(&mut item_fn.sig, &mut item_fn.block);
+        visit_mut::visit_impl_item_fn_mut(self, item_fn);
+    }
+
+    fn visit_trait_item_fn_mut(&mut self, item_fn: &mut syn::TraitItemFn) {
+        if let Some(block) = &mut item_fn.default {
+            self.shorten(&mut item_fn.sig, block);
+        }
+        visit_mut::visit_trait_item_fn_mut(self, item_fn);
+    }
+}
+
+// `a`, ..., `z`, `aa`, `ab`, ...
+fn short_name(mut index: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'a' + (index % 26) as u8);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap()
+}
+
+#[derive(Default)]
+struct ItemNames {
+    names: HashSet<String>,
+}
+
+impl<'ast> Visit<'ast> for ItemNames {
+    fn visit_item(&mut self, item: &'ast Item) {
+        let ident = match item {
+            Item::Const(item) => Some(&item.ident),
+            Item::Enum(item) => Some(&item.ident),
+            Item::ExternCrate(item) => Some(&item.ident),
+            Item::Fn(item) => Some(&item.sig.ident),
+            Item::Macro(item) => item.ident.as_ref(),
+            Item::Mod(item) => Some(&item.ident),
+            Item::Static(item) => Some(&item.ident),
+            Item::Struct(item) => Some(&item.ident),
+            Item::Trait(item) => Some(&item.ident),
+            Item::TraitAlias(item) => Some(&item.ident),
+            Item::Type(item) => Some(&item.ident),
+            Item::Union(item) => Some(&item.ident),
+            _ => None,
+        };
+        self.names.extend(ident.map(Ident::to_string));
+        visit::visit_item(self, item);
+    }
+
+    fn visit_variant(&mut self, variant: &'ast syn::Variant) {
+        self.names.insert(variant.ident.to_string());
+        visit::visit_variant(self, variant);
+    }
+
+    fn visit_foreign_item_fn(&mut self, item: &'ast syn::ForeignItemFn) {
+        self.names.insert(item.sig.ident.to_string());
+    }
+
+    fn visit_foreign_item_static(&mut self, item: &'ast syn::ForeignItemStatic) {
+        self.names.insert(item.ident.to_string());
+    }
+
+    fn visit_use_tree(&mut self, tree: &'ast UseTree) {
+        match tree {
+            UseTree::Name(name) => self.names.insert(name.ident.to_string()),
+            UseTree::Rename(rename) => self.names.insert(rename.rename.to_string()),
+            _ => false,
+        };
+        visit::visit_use_tree(self, tree);
+    }
+}
+
+// Names a function binds, and names that can't be renamed safely
+#[derive(Default)]
+struct Bindings {
+    names: HashSet<String>,
+    pinned: HashSet<String>,
+}
+
+impl<'ast> Visit<'ast> for Bindings {
+    fn visit_pat_ident(&mut self, pat_ident: &'ast PatIdent) {
+        self.names.insert(pat_ident.ident.to_string());
+        visit::visit_pat_ident(self, pat_ident);
+    }
+
+    // Nested items are renamed on their own, but a `macro_rules!` defined in the
+    // body could use the function's locals
+    fn visit_item(&mut self, item: &'ast Item) {
+        collect_pinned(item.to_token_stream(), &mut self.pinned);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        pin_format_arguments(mac.tokens.clone(), &mut self.pinned);
+        match expression_arguments(mac) {
+            Some(args) => {
+                for arg in &args {
+                    self.visit_expr(arg);
+                }
+            }
+            None => collect_pinned(mac.tokens.clone(), &mut self.pinned),
+        }
+    }
+}
+
+fn collect_pinned(tokens: TokenStream, pinned: &mut HashSet<String>) {
+    let mut idents = Vec::new();
+    collect_idents(tokens, &mut idents);
+    pinned.extend(idents);
+}
+
+// `{name}` in a format string captures the variable `name`, and so do
+// `width$` and `prec$` in a format spec such as `{:>width$.prec$}`
+fn pin_format_arguments(tokens: TokenStream, pinned: &mut HashSet<String>) {
+    let name = Regex::new(r"\{([A-Za-z_][A-Za-z0-9_]*)").unwrap();
+    let spec = Regex::new(r"\{[^{}]*\}").unwrap();
+    let count = Regex::new(r"([A-Za-z_][A-Za-z0-9_]*)\$").unwrap();
+    for token in tokens {
+        match token {
+            TokenTree::Literal(literal) => {
+                let literal = literal.to_string();
+                pinned.extend(name.captures_iter(&literal).map(|caps| caps[1].to_string()));
+                for placeholder in spec.find_iter(&literal) {
+                    pinned.extend(
+                        count
+                            .captures_iter(placeholder.as_str())
+                            .map(|caps| caps[1].to_string()),
+                    );
+                }
+            }
+            TokenTree::Group(group) => pin_format_arguments(group.stream(), pinned),
+            _ => {}
+        }
+    }
+}
+
+fn expression_arguments(mac: &Macro) -> Option<Punctuated<Expr, Token![,]>> {
+    let name = mac.path.segments.last()?.ident.to_string();
+    if !EXPRESSION_MACROS.contains(&name.as_str()) {
+        return None;
+    }
+    mac.parse_body_with(Punctuated::parse_terminated).ok()
+}
+
+struct Renamer {
+    renames: HashMap<String, String>,
+}
+
+impl Renamer {
+    fn rename(&self, ident: &mut Ident) {
+        if let Some(short) = self.renames.get(&ident.to_string()) {
+            *ident = Ident::new(short, ident.span());
+        }
+    }
+}
+
+impl VisitMut for Renamer {
+    fn visit_pat_ident_mut(&mut self, pat_ident: &mut PatIdent) {
+        self.rename(&mut pat_ident.ident);
+        visit_mut::visit_pat_ident_mut(self, pat_ident);
+    }
+
+    fn visit_expr_path_mut(&mut self, expr_path: &mut ExprPath) {
+        let path = &mut expr_path.path;
+        if expr_path.qself.is_none() && path.leading_colon.is_none() && path.segments.len() == 1 {
+            let segment = &mut path.segments[0];
+            if segment.arguments.is_empty() {
+                self.rename(&mut segment.ident);
+            }
+        }
+    }
+
+    // `Point { x }` has to become `Point { x: a }`
+    fn visit_field_value_mut(&mut self, field: &mut FieldValue) {
+        if matches!(&field.member, Member::Named(name) if self.renames.contains_key(&name.to_string()))
+        {
+            field.colon_token.get_or_insert_with(Default::default);
+        }
+        visit_mut::visit_field_value_mut(self, field);
+    }
+
+    fn visit_field_pat_mut(&mut self, field: &mut FieldPat) {
+        if matches!(&field.member, Member::Named(name) if self.renames.contains_key(&name.to_string()))
+        {
+            field.colon_token.get_or_insert_with(Default::default);
+        }
+        visit_mut::visit_field_pat_mut(self, field);
+    }
+
+    fn visit_item_mut(&mut self, _: &mut Item) {}
+
+    fn visit_macro_mut(&mut self, mac: &mut Macro) {
+        let Some(mut args) = expression_arguments(mac) else {
+            return;
+        };
+        for arg in &mut args {
+            match arg {
+                // Named format arguments keep their name
+                Expr::Assign(assign) => self.visit_expr_mut(&mut assign.right),
+                arg => self.visit_expr_mut(arg),
+            }
+        }
+        mac.tokens = args.into_token_stream();
+    }
+}
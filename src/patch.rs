@@ -0,0 +1,431 @@
+// `cargo rustmerge apply`: the edits made to a merged file, as changes to the
+// files it was merged from.
+//
+// The edited file and a fresh merge of the tree are both split back into files
+// the way `unmerge` does it, so each change lands in the file whose comment heads
+// its block, and the two versions of every file are diffed line by line. The
+// fresh version's lines are then matched to the original file's by their text,
+// which formatting mostly leaves alone, to find where each change goes. A change
+// to lines the merge reformatted or dropped (comments, blank lines in between)
+// has no safe place to go and is refused rather than guessed at. Merges made with
+// `--preserve-comments` keep every line, so their changes always map.
+//
+// Diffs take space linear in the length of the texts, since bundles run to
+// thousands of lines. `--check` only counts the lines its summary of a stale
+// output needs, without working out which ones changed.
+
+use std::fmt::Write;
+use std::fs;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use crate::unmerge::{unmerge, SourceFile};
+
+// Diff context, as in `diff -u`
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilePatch {
+    pub file: PathBuf,
+    pub original: Option<String>, // None for a file the edits add
+    pub patched: Option<String>,  // None for a file the edits remove
+}
+
+// A run of lines of one text replaced by a run of lines of another
+#[derive(Debug, Clone, PartialEq)]
+struct Hunk {
+    old: Range<usize>,
+    new: Range<usize>,
+}
+
+// The changes `edited` makes to `fresh`, a merge of the current tree, on the
+// original files; `file_of` locates the file behind the path in a file comment
+pub fn file_patches(
+    fresh: &str,
+    edited: &str,
+    file_of: impl Fn(&str) -> PathBuf,
+) -> Result<Vec<FilePatch>> {
+    let fresh = unmerge(fresh).context("Failed to split up a fresh merge of the tree")?;
+    let edited = unmerge(edited).context("Failed to split up the edited file")?;
+
+    let mut patches = Vec::new();
+    let mut unplaced = Vec::new();
+    for file in &edited {
+        let path = file.path.to_string_lossy();
+        let original_file = file_of(&path);
+        let Some(before) = fresh.iter().find(|before| before.path == file.path) else {
+            patches.push(FilePatch {
+                file: original_file,
+                original: None,
+                patched: Some(file.text.clone()),
+            });
+            continue;
+        };
+        if before.text == file.text {
+            continue;
+        }
+
+        let original = fs::read_to_string(&original_file)
+            .with_context(|| format!("Failed to read {:?}", original_file))?;
+        match patch_file(&original, &before.text, &file.text) {
+            Ok(patched) => patches.push(FilePatch {
+                file: original_file,
+                original: Some(original),
+                patched: Some(patched),
+            }),
+            Err(lines) => unplaced.extend(
+                lines
+                    .into_iter()
+                    .map(|line| format!("  {}: `{}`", path, line.trim())),
+            ),
+        }
+    }
+
+    for SourceFile { path, .. } in &fresh {
+        if !edited.iter().any(|file| file.path == *path) {
+            let original_file = file_of(&path.to_string_lossy());
+            let original = fs::read_to_string(&original_file)
+                .with_context(|| format!("Failed to read {:?}", original_file))?;
+            patches.push(FilePatch {
+                file: original_file,
+                original: Some(original),
+                patched: None,
+            });
+        }
+    }
+
+    if !unplaced.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Some changes can't be mapped back to their files (changes: {}), because the merge reformatted or dropped the lines around them; a merge made with --preserve-comments keeps every line:\n{}",
+            unplaced.len(),
+            unplaced.join("\n")
+        ));
+    }
+    Ok(patches)
+}
+
+// `original` with the changes made to `before`, its merged form, in `after`. On
+// failure, the first line of each change that couldn't be placed.
+fn patch_file(original: &str, before: &str, after: &str) -> Result<String, Vec<String>> {
+    let original: Vec<&str> = original.split_inclusive('\n').collect();
+    let before: Vec<&str> = before.split_inclusive('\n').collect();
+    let after: Vec<&str> = after.split_inclusive('\n').collect();
+
+    // Where each line of the merged form is in the original
+    let mut placed = vec![None; before.len()];
+    let same_code = |a: &str, b: &str| !a.trim().is_empty() && a.trim() == b.trim();
+    for (i, j) in common_lines(&before, &original, same_code) {
+        placed[i] = Some(j);
+    }
+
+    let mut changes = Vec::new();
+    let mut unplaced = Vec::new();
+    for hunk in hunks(&before, &after, |a, b| a == b) {
+        match place(&hunk.old, &placed, &original) {
+            Some(old) => changes.push(Hunk { old, new: hunk.new }),
+            None => {
+                let line = before
+                    .get(hunk.old.start)
+                    .or(after.get(hunk.new.start))
+                    .map_or("", |line| *line);
+                unplaced.push(line.to_string());
+            }
+        }
+    }
+    if !unplaced.is_empty() {
+        return Err(unplaced);
+    }
+
+    let mut patched = String::new();
+    let mut cursor = 0;
+    for change in changes {
+        patched.extend(original[cursor..change.old.start].iter().copied());
+        patched.extend(after[change.new].iter().copied());
+        cursor = change.old.end;
+    }
+    patched.extend(original[cursor..].iter().copied());
+    Ok(patched)
+}
+
+// The lines of the original that `lines` of the merged form came from: every one
+// of them must be placed, and whatever lies between them blank
+fn place(
+    lines: &Range<usize>,
+    placed: &[Option<usize>],
+    original: &[&str],
+) -> Option<Range<usize>> {
+    if lines.is_empty() {
+        // Insertions go after the line before them, or else before the line after
+        let previous = lines.start.checked_sub(1).map(|i| placed[i]);
+        return match (previous, placed.get(lines.start)) {
+            (Some(Some(previous)), _) => Some(previous + 1..previous + 1),
+            (_, Some(Some(next))) => Some(*next..*next),
+            (None, None) => Some(original.len()..original.len()),
+            _ => None,
+        };
+    }
+
+    let placed: Vec<usize> = placed[lines.clone()]
+        .iter()
+        .copied()
+        .collect::<Option<_>>()?;
+    let range = placed[0]..placed[placed.len() - 1] + 1;
+    let covered = range
+        .clone()
+        .all(|j| placed.contains(&j) || original[j].trim().is_empty());
+    covered.then_some(range)
+}
+
+// A unified diff of a file, as `diff -u` would write it
+pub fn unified_diff(label: &str, original: Option<&str>, patched: Option<&str>) -> String {
+    let old: Vec<&str> = original.unwrap_or("").split_inclusive('\n').collect();
+    let new: Vec<&str> = patched.unwrap_or("").split_inclusive('\n').collect();
+
+    let mut diff = String::new();
+    match original {
+        Some(_) => writeln!(diff, "--- a/{}", label).unwrap(),
+        None => writeln!(diff, "--- /dev/null").unwrap(),
+    }
+    match patched {
+        Some(_) => writeln!(diff, "+++ b/{}", label).unwrap(),
+        None => writeln!(diff, "+++ /dev/null").unwrap(),
+    }
+
+    // Hunks whose context would touch are written as one
+    let hunks = hunks(&old, &new, |a, b| a == b);
+    let mut groups: Vec<Vec<Hunk>> = Vec::new();
+    for hunk in hunks {
+        match groups.last_mut() {
+            Some(group) if hunk.old.start - group[group.len() - 1].old.end <= 2 * CONTEXT => {
+                group.push(hunk)
+            }
+            _ => groups.push(vec![hunk]),
+        }
+    }
+
+    for group in groups {
+        let (first, last) = (&group[0], &group[group.len() - 1]);
+        let before = first.old.start.min(CONTEXT);
+        let after = (old.len() - last.old.end).min(CONTEXT);
+        let old_range = first.old.start - before..last.old.end + after;
+        let new_range = first.new.start - before..last.new.end + after;
+        writeln!(
+            diff,
+            "@@ -{} +{} @@",
+            hunk_range(&old_range),
+            hunk_range(&new_range)
+        )
+        .unwrap();
+
+        let mut cursor = old_range.start;
+        for hunk in &group {
+            push_lines(&mut diff, ' ', &old[cursor..hunk.old.start]);
+            push_lines(&mut diff, '-', &old[hunk.old.clone()]);
+            push_lines(&mut diff, '+', &new[hunk.new.clone()]);
+            cursor = hunk.old.end;
+        }
+        push_lines(&mut diff, ' ', &old[cursor..old_range.end]);
+    }
+    diff
+}
+
+// How many lines `new` adds to `old` and removes from it, and the first line of
+// `old` where they differ. This only measures the shortest diff, rather than
+// finding it the way `hunks` does.
+pub fn diff_summary(old: &str, new: &str) -> (usize, usize, usize) {
+    let old: Vec<&str> = old.split_inclusive('\n').collect();
+    let new: Vec<&str> = new.split_inclusive('\n').collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    if prefix == old.len() && prefix == new.len() {
+        return (0, 0, 0);
+    }
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+
+    // The lines added and removed add up to the distance and differ by the change in length
+    let distance = edit_distance(old, new);
+    let added = (distance + new.len() - old.len()) / 2;
+    (added, distance - added, prefix + 1)
+}
+
+// The number of lines a shortest diff from `a` to `b` adds and removes, by
+// Myers' O(ND) algorithm, which keeps only the furthest point reached on each
+// diagonal and so needs space linear in the length of the files
+fn edit_distance(a: &[&str], b: &[&str]) -> usize {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    // furthest[k + max] is how far into `a` diagonal k = x - y gets
+    let mut furthest = vec![0isize; 2 * max as usize + 2];
+    let index = |k: isize| (k + max) as usize;
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && furthest[index(k - 1)] < furthest[index(k + 1)]) {
+                furthest[index(k + 1)]
+            } else {
+                furthest[index(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            furthest[index(k)] = x;
+            if x >= n && y >= m {
+                return d as usize;
+            }
+        }
+    }
+    max as usize
+}
+
+// `start,length`, where an empty range starts at the line before it and a
+// length of one goes without saying
+fn hunk_range(range: &Range<usize>) -> String {
+    match range.len() {
+        0 => format!("{},0", range.start),
+        1 => format!("{}", range.start + 1),
+        length => format!("{},{}", range.start + 1, length),
+    }
+}
+
+fn push_lines(diff: &mut String, prefix: char, lines: &[&str]) {
+    for line in lines {
+        diff.push(prefix);
+        diff.push_str(line);
+        if !line.ends_with('\n') {
+            diff.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+// What differs between `a` and `b`, in order
+fn hunks(a: &[&str], b: &[&str], eq: impl Fn(&str, &str) -> bool) -> Vec<Hunk> {
+    let mut hunks = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for (next_i, next_j) in common_lines(a, b, eq)
+        .into_iter()
+        .chain([(a.len(), b.len())])
+    {
+        if next_i > i || next_j > j {
+            hunks.push(Hunk {
+                old: i..next_i,
+                new: j..next_j,
+            });
+        }
+        (i, j) = (next_i + 1, next_j + 1);
+    }
+    hunks
+}
+
+// Pairs of lines of `a` and `b` that are the same, as many as can be in order: a
+// longest common subsequence, found in linear space by splitting the problem at
+// the middle of a shortest diff (Myers' refinement) rather than with a table of
+// every pair of lines
+fn common_lines(a: &[&str], b: &[&str], eq: impl Fn(&str, &str) -> bool) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    collect_common_lines(a, b, (0, 0), &eq, &mut pairs);
+    pairs
+}
+
+// `offset` is where `a` and `b` start in the whole texts
+fn collect_common_lines(
+    a: &[&str],
+    b: &[&str],
+    offset: (usize, usize),
+    eq: &impl Fn(&str, &str) -> bool,
+    pairs: &mut Vec<(usize, usize)>,
+) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| eq(x, y)).count();
+    pairs.extend((0..prefix).map(|k| (offset.0 + k, offset.1 + k)));
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    let offset = (offset.0 + prefix, offset.1 + prefix);
+    let suffix = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| eq(x, y))
+        .count();
+    let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+
+    // With the ends set aside, a diff of one line leaves one side empty, and
+    // anything longer splits into two shorter ones
+    if !a.is_empty() && !b.is_empty() {
+        let (start, end) = middle_snake(a, b, eq);
+        collect_common_lines(&a[..start.0], &b[..start.1], offset, eq, pairs);
+        pairs
+            .extend((0..end.0 - start.0).map(|k| (offset.0 + start.0 + k, offset.1 + start.1 + k)));
+        collect_common_lines(
+            &a[end.0..],
+            &b[end.1..],
+            (offset.0 + end.0, offset.1 + end.1),
+            eq,
+            pairs,
+        );
+    }
+    pairs.extend((0..suffix).map(|k| (offset.0 + a.len() + k, offset.1 + b.len() + k)));
+}
+
+// The start and end of the run of equal lines in the middle of a shortest diff
+// from `a` to `b`, found by searching from both ends at once until they meet
+fn middle_snake(
+    a: &[&str],
+    b: &[&str],
+    eq: &impl Fn(&str, &str) -> bool,
+) -> ((usize, usize), (usize, usize)) {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let max = (n + m + 1) / 2;
+    // forward[k] is how far into `a` diagonal k = x - y gets from the start, and
+    // backward[k] how far from the end, on the diagonals of the reversed texts
+    let mut forward = vec![0isize; 2 * max as usize + 3];
+    let mut backward = vec![0isize; 2 * max as usize + 3];
+    let index = |k: isize| (k + max + 1) as usize;
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let start = if k == -d || (k != d && forward[index(k - 1)] < forward[index(k + 1)]) {
+                forward[index(k + 1)]
+            } else {
+                forward[index(k - 1)] + 1
+            };
+            let mut x = start;
+            while x < n && x - k < m && eq(a[x as usize], b[(x - k) as usize]) {
+                x += 1;
+            }
+            forward[index(k)] = x;
+            if delta % 2 != 0 && (delta - k).abs() < d && x + backward[index(delta - k)] >= n {
+                let (start, end) = ((start, start - k), (x, x - k));
+                return (
+                    (start.0 as usize, start.1 as usize),
+                    (end.0 as usize, end.1 as usize),
+                );
+            }
+        }
+        for k in (-d..=d).step_by(2) {
+            let start = if k == -d || (k != d && backward[index(k - 1)] < backward[index(k + 1)]) {
+                backward[index(k + 1)]
+            } else {
+                backward[index(k - 1)] + 1
+            };
+            let mut x = start;
+            while x < n && x - k < m && eq(a[(n - 1 - x) as usize], b[(m - 1 - x + k) as usize]) {
+                x += 1;
+            }
+            backward[index(k)] = x;
+            if delta % 2 == 0 && (delta - k).abs() <= d && x + forward[index(delta - k)] >= n {
+                return (
+                    ((n - x) as usize, (m - x + k) as usize),
+                    ((n - start) as usize, (m - start + k) as usize),
+                );
+            }
+        }
+    }
+    unreachable!("the searches meet by the middle of the longest possible diff")
+}
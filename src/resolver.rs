@@ -0,0 +1,222 @@
+// Module file resolution, following the rules of the Rust reference:
+// https://doc.rust-lang.org/reference/items/modules.html#module-source-filenames
+//
+// Every block of items (a file or an inline `mod { }`) carries a `ModuleDir` that
+// says where the `mod foo;` declarations inside it are looked up, the same way
+// rustc tracks directory ownership while expanding a crate.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use syn::ext::IdentExt;
+use syn::punctuated::Punctuated;
+use syn::{Attribute, Expr, ExprLit, Ident, Lit, Meta, Token};
+
+#[derive(Debug, Clone)]
+pub struct ModuleDir {
+    pub path: PathBuf,            // Directory `#[path]` attributes are relative to
+    pub relative: Option<String>, // Set for non-mod-rs files: `foo.rs` owns the `foo/` directory
+}
+
+#[derive(Debug)]
+pub struct ResolvedModule {
+    pub file: PathBuf,  // Source file of the module
+    pub dir: ModuleDir, // Context for the `mod` declarations inside that file
+}
+
+impl ModuleDir {
+    // Crate roots (`lib.rs`, `main.rs`) are mod-rs files: children sit next to them
+    pub fn crate_root(root_file: &Path) -> Self {
+        ModuleDir {
+            path: parent_dir(root_file),
+            relative: None,
+        }
+    }
+
+    // Context for the items of an inline `mod name { ... }` block declared here
+    pub fn inline(&self, ident: &Ident, attrs: &[Attribute]) -> Self {
+        // On inline modules `#[path]` names a directory rather than a file
+        if let Some(path) = path_attr(attrs) {
+            return ModuleDir {
+                path: self.path.join(path),
+                relative: None,
+            };
+        }
+
+        let mut path = self.path.clone();
+        if let Some(relative) = &self.relative {
+            path.push(relative);
+        }
+        path.push(module_name(ident));
+        ModuleDir {
+            path,
+            relative: None,
+        }
+    }
+
+    // Locate the file behind a `mod name;` declared here
+    pub fn resolve(&self, ident: &Ident, attrs: &[Attribute]) -> Result<ResolvedModule> {
+        let name = module_name(ident);
+
+        if let Some(path) = path_attr(attrs) {
+            let file = self.path.join(path);
+            if !file.is_file() {
+                return Err(anyhow::anyhow!(
+                    "file not found for module `{}`: {:?} given by its #[path] attribute does not exist",
+                    name,
+                    file
+                ));
+            }
+            // Files loaded through `#[path]` are treated as mod-rs files, so their
+            // own `mod` declarations resolve next to them
+            return Ok(ResolvedModule {
+                dir: ModuleDir::crate_root(&file),
+                file,
+            });
+        }
+
+        let (default_path, secondary_path) = self.candidates(ident);
+        match (default_path.is_file(), secondary_path.is_file()) {
+            (true, false) => Ok(ResolvedModule {
+                dir: self.file_dir(ident, &default_path),
+                file: default_path,
+            }),
+            (false, true) => Ok(ResolvedModule {
+                dir: self.file_dir(ident, &secondary_path),
+                file: secondary_path,
+            }),
+            (false, false) => Err(anyhow::anyhow!(
+                "error[E0583]: file not found for module `{}`; create file {:?} or {:?}",
+                name,
+                default_path,
+                secondary_path
+            )),
+            (true, true) => Err(anyhow::anyhow!(
+                "error[E0761]: file for module `{}` found at both {:?} and {:?}; delete or rename one of them to remove the ambiguity",
+                name,
+                default_path,
+                secondary_path
+            )),
+        }
+    }
+}
+
+impl ModuleDir {
+    // Where a `mod name;` declared here is looked for without `#[path]`:
+    // `name.rs` and `name/mod.rs`
+    pub fn candidates(&self, ident: &Ident) -> (PathBuf, PathBuf) {
+        let name = module_name(ident);
+        let mut dir = self.path.clone();
+        if let Some(relative) = &self.relative {
+            dir.push(relative);
+        }
+        (
+            dir.join(format!("{}.rs", name)),
+            dir.join(&name).join("mod.rs"),
+        )
+    }
+
+    // Context for the `mod` declarations inside `file`, the file of the module
+    // `name` declared here
+    pub fn file_dir(&self, ident: &Ident, file: &Path) -> ModuleDir {
+        let (default_path, _) = self.candidates(ident);
+        if file == default_path {
+            ModuleDir {
+                path: parent_dir(file),
+                relative: Some(module_name(ident)),
+            }
+        } else {
+            // `name/mod.rs`, and files loaded through `#[path]`, are treated as
+            // mod-rs files, so their own `mod` declarations resolve next to them
+            ModuleDir::crate_root(file)
+        }
+    }
+}
+
+// File system name of a module: `r#type` lives in `type.rs`
+pub fn module_name(ident: &Ident) -> String {
+    ident.unraw().to_string()
+}
+
+fn parent_dir(file: &Path) -> PathBuf {
+    file.parent().unwrap_or(Path::new("")).to_path_buf()
+}
+
+// Extract the module path from `#[path = "..."]`, or from `#[cfg_attr(pred, path = "...")]`
+// when `pred` holds for the host we're running on
+pub fn path_attr(attrs: &[Attribute]) -> Option<String> {
+    attrs.iter().find_map(|attr| path_from_meta(&attr.meta))
+}
+
+fn path_from_meta(meta: &Meta) -> Option<String> {
+    match meta {
+        Meta::NameValue(name_value) if name_value.path.is_ident("path") => {
+            match &name_value.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(lit), ..
+                }) => Some(lit.value()),
+                _ => None,
+            }
+        }
+        Meta::List(list) if list.path.is_ident("cfg_attr") => {
+            let nested = list
+                .parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+                .ok()?;
+            let mut nested = nested.iter();
+            if !cfg_matches_host(nested.next()?) {
+                return None;
+            }
+            nested.find_map(path_from_meta)
+        }
+        _ => None,
+    }
+}
+
+// Evaluate a `cfg` predicate against the host platform. Only target keys can be
+// answered here; anything else (e.g. `feature = "..."`) is treated as unset.
+pub fn cfg_matches_host(meta: &Meta) -> bool {
+    match meta {
+        Meta::Path(path) => {
+            (path.is_ident("unix") && cfg!(unix)) || (path.is_ident("windows") && cfg!(windows))
+        }
+        Meta::NameValue(name_value) => {
+            let value = match &name_value.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(lit), ..
+                }) => lit.value(),
+                _ => return false,
+            };
+            let key = name_value.path.get_ident().map(|ident| ident.to_string());
+            let host = match key.as_deref() {
+                Some("target_os") => env::consts::OS.to_string(),
+                Some("target_family") => env::consts::FAMILY.to_string(),
+                Some("target_arch") => env::consts::ARCH.to_string(),
+                Some("target_pointer_width") => (std::mem::size_of::<usize>() * 8).to_string(),
+                Some("target_endian") => if cfg!(target_endian = "little") {
+                    "little"
+                } else {
+                    "big"
+                }
+                .to_string(),
+                _ => return false,
+            };
+            host == value
+        }
+        Meta::List(list) => {
+            let Ok(nested) = list.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+            else {
+                return false;
+            };
+            if list.path.is_ident("all") {
+                nested.iter().all(cfg_matches_host)
+            } else if list.path.is_ident("any") {
+                nested.iter().any(cfg_matches_host)
+            } else if list.path.is_ident("not") {
+                nested.len() == 1 && !cfg_matches_host(&nested[0])
+            } else {
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,319 @@
+// Path rewriting for crates bundled into one file as top-level modules.
+//
+// Once a crate `foo` is merged in as `mod foo`, code that used it has to say
+// `crate::foo::` instead of `foo::`, and the crate's own `crate::` and `$crate::`
+// paths have to point at `crate::foo::`. This works on raw tokens rather than the
+// syntax tree, so paths inside macro bodies and `macro_rules!` are covered too.
+// `#[macro_export]` macros are the exception: they live at the root of whatever
+// crate defines them, which is now the bundle, so `foo::mac!` becomes `crate::mac!`.
+
+use std::collections::{HashMap, HashSet};
+
+use proc_macro2::{Delimiter, Group, Ident, Punct, Spacing, Span, TokenStream, TokenTree};
+use syn::Item;
+
+pub struct CratePaths<'a> {
+    crates: &'a HashMap<String, String>, // Names code uses for the bundled crates, to their modules
+    exported_macros: &'a HashSet<String>, // `#[macro_export]` macros of those crates
+}
+
+impl<'a> CratePaths<'a> {
+    pub fn new(crates: &'a HashMap<String, String>, exported_macros: &'a HashSet<String>) -> Self {
+        CratePaths {
+            crates,
+            exported_macros,
+        }
+    }
+
+    // Rewrite the tokens of one crate of the bundle. `own_crate` is the module an
+    // embedded crate was merged into, or `None` for the bundle's main crate.
+    pub fn rewrite(&self, tokens: TokenStream, own_crate: Option<&str>) -> TokenStream {
+        let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+        let mut output: Vec<TokenTree> = Vec::with_capacity(tokens.len());
+        let mut i = 0;
+
+        while i < tokens.len() {
+            match &tokens[i] {
+                TokenTree::Group(group) => {
+                    let mut rewritten =
+                        Group::new(group.delimiter(), self.rewrite(group.stream(), own_crate));
+                    rewritten.set_span(group.span());
+                    output.push(rewritten.into());
+                    i += 1;
+                }
+                TokenTree::Ident(_) => {
+                    if let Some(consumed) = self.rewrite_crate_import(&tokens[i..], &mut output) {
+                        i += consumed;
+                    } else if let Some(consumed) =
+                        self.rewrite_path_start(&tokens[i..], own_crate, &mut output)
+                    {
+                        i += consumed;
+                    } else {
+                        output.push(tokens[i].clone());
+                        i += 1;
+                    }
+                }
+                token => {
+                    output.push(token.clone());
+                    i += 1;
+                }
+            }
+        }
+
+        output.into_iter().collect()
+    }
+
+    // `crate::`, `$crate::`, `foo::` and `::foo::` at the start of a path
+    fn rewrite_path_start(
+        &self,
+        tokens: &[TokenTree],
+        own_crate: Option<&str>,
+        output: &mut Vec<TokenTree>,
+    ) -> Option<usize> {
+        let TokenTree::Ident(ident) = &tokens[0] else {
+            return None;
+        };
+        if !is_path_sep(&tokens[1..]) || continues_path(output) {
+            return None;
+        }
+        let macro_follows = self.is_exported_macro(&tokens[3..]);
+
+        if ident == "crate" {
+            let own_crate = own_crate?;
+            output.push(tokens[0].clone());
+            output.extend(path_sep());
+            if !macro_follows {
+                output.push(ident_token(own_crate));
+                output.extend(path_sep());
+            }
+            return Some(3);
+        }
+
+        let module = self.crates.get(&ident.to_string())?;
+        if Some(module.as_str()) == own_crate {
+            return None;
+        }
+        // `::foo::bar` names the extern crate explicitly; the leading `::` has to go
+        if ends_with_path_sep(output) {
+            output.truncate(output.len() - 2);
+        }
+        output.push(ident_token("crate"));
+        output.extend(path_sep());
+        if macro_follows {
+            return Some(3);
+        }
+        output.push(ident_token(module));
+        output.extend(path_sep());
+        match tokens.get(3) {
+            Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Brace => {
+                output.push(self.without_exported_macros(group).into());
+                Some(4)
+            }
+            _ => Some(3),
+        }
+    }
+
+    // `extern crate foo;` and `use foo;` only brought the crate into scope, which the
+    // rewritten paths no longer need, and `use foo::mac;` is covered by the crate's
+    // `#[macro_use]` module (importing `mac` at the bundle root would even clash
+    // with the exported macro itself). Renaming forms become `use crate::foo as bar;`.
+    fn rewrite_crate_import(
+        &self,
+        tokens: &[TokenTree],
+        output: &mut Vec<TokenTree>,
+    ) -> Option<usize> {
+        let name_at = if is_ident(tokens.first(), "extern") && is_ident(tokens.get(1), "crate") {
+            2
+        } else if is_ident(tokens.first(), "use") {
+            1
+        } else {
+            return None;
+        };
+        let TokenTree::Ident(name) = tokens.get(name_at)? else {
+            return None;
+        };
+        let module = self.crates.get(&name.to_string())?;
+
+        let mut imported = ident_token(module);
+        let mut end = name_at + 1;
+        if name_at == 1 && is_path_sep(&tokens[end..]) && self.is_exported_macro(&tokens[end + 2..])
+        {
+            imported = tokens[end + 2].clone();
+            end += 3;
+        }
+        let (alias, consumed) = if is_punct(tokens.get(end), ';') {
+            (None, end + 1)
+        } else if is_ident(tokens.get(end), "as") && is_punct(tokens.get(end + 2), ';') {
+            (tokens.get(end + 1).cloned(), end + 3)
+        } else {
+            return None;
+        };
+
+        let vis = take_visibility(output);
+        let attrs = take_outer_attributes(output);
+        if let Some(alias) = alias {
+            // `#[macro_use]` and friends of `extern crate` have no meaning on a `use`
+            if name_at == 1 {
+                output.extend(attrs);
+            }
+            output.extend(vis);
+            output.push(ident_token("use"));
+            output.push(ident_token("crate"));
+            output.extend(path_sep());
+            output.push(imported);
+            output.push(ident_token("as"));
+            output.push(alias);
+            output.push(Punct::new(';', Spacing::Alone).into());
+        }
+        Some(consumed)
+    }
+
+    // Drop exported macros from `use foo::{mac, Type}`, for the same reason
+    fn without_exported_macros(&self, group: &Group) -> Group {
+        let mut entries: Vec<Vec<TokenTree>> = vec![Vec::new()];
+        for token in group.stream() {
+            if is_punct(Some(&token), ',') {
+                entries.push(Vec::new());
+            } else {
+                entries.last_mut().unwrap().push(token);
+            }
+        }
+        entries.retain(|entry| {
+            !(entry.is_empty() || (entry.len() == 1 && self.is_exported_macro(entry)))
+        });
+
+        let mut stream = Vec::new();
+        for (i, entry) in entries.into_iter().enumerate() {
+            if i > 0 {
+                stream.push(Punct::new(',', Spacing::Alone).into());
+            }
+            stream.extend(entry);
+        }
+        let mut filtered = Group::new(group.delimiter(), stream.into_iter().collect());
+        filtered.set_span(group.span());
+        filtered
+    }
+
+    // `mac` in `foo::mac!(...)` or `use foo::mac;`, for an exported macro `mac`
+    fn is_exported_macro(&self, tokens: &[TokenTree]) -> bool {
+        match tokens.first() {
+            Some(TokenTree::Ident(ident)) => {
+                self.exported_macros.contains(&ident.to_string()) && !is_path_sep(&tokens[1..])
+            }
+            _ => false,
+        }
+    }
+}
+
+// Names of the `#[macro_export]` macros defined in `contents`
+pub fn exported_macros<'a>(contents: impl IntoIterator<Item = &'a TokenStream>) -> HashSet<String> {
+    let mut macros = HashSet::new();
+    for content in contents {
+        if let Ok(file) = syn::parse2::<syn::File>(content.clone()) {
+            collect_exported_macros(&file.items, &mut macros);
+        }
+    }
+    macros
+}
+
+fn collect_exported_macros(items: &[Item], macros: &mut HashSet<String>) {
+    for item in items {
+        match item {
+            Item::Macro(item_macro)
+                if item_macro
+                    .attrs
+                    .iter()
+                    .any(|attr| attr.path().is_ident("macro_export")) =>
+            {
+                if let Some(ident) = &item_macro.ident {
+                    macros.insert(ident.to_string());
+                }
+            }
+            Item::Mod(item_mod) => {
+                if let Some((_, items)) = &item_mod.content {
+                    collect_exported_macros(items, macros);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn path_sep() -> [TokenTree; 2] {
+    [
+        Punct::new(':', Spacing::Joint).into(),
+        Punct::new(':', Spacing::Alone).into(),
+    ]
+}
+
+fn ident_token(name: &str) -> TokenTree {
+    Ident::new(name, Span::call_site()).into()
+}
+
+fn is_ident(token: Option<&TokenTree>, name: &str) -> bool {
+    matches!(token, Some(TokenTree::Ident(ident)) if ident == name)
+}
+
+fn is_punct(token: Option<&TokenTree>, ch: char) -> bool {
+    matches!(token, Some(TokenTree::Punct(punct)) if punct.as_char() == ch)
+}
+
+fn is_path_sep(tokens: &[TokenTree]) -> bool {
+    matches!(
+        tokens,
+        [TokenTree::Punct(first), TokenTree::Punct(second), ..]
+            if first.as_char() == ':' && first.spacing() == Spacing::Joint && second.as_char() == ':'
+    )
+}
+
+fn ends_with_path_sep(output: &[TokenTree]) -> bool {
+    output.len() >= 2 && is_path_sep(&output[output.len() - 2..])
+}
+
+// Whether the next identifier is a later segment of a path (`a::b`) rather than
+// the first segment of an absolute path (`::b`, `use ::b`, `<T as ::b>`)
+fn continues_path(output: &[TokenTree]) -> bool {
+    if !ends_with_path_sep(output) {
+        return false;
+    }
+    match output.len().checked_sub(3).map(|i| &output[i]) {
+        Some(TokenTree::Ident(ident)) => {
+            ["self", "super", "crate", "Self"].contains(&ident.to_string().as_str())
+                || syn::parse2::<syn::Ident>(TokenTree::Ident(ident.clone()).into()).is_ok()
+        }
+        Some(TokenTree::Punct(punct)) => punct.as_char() == '>',
+        _ => false,
+    }
+}
+
+// Pop a trailing `pub` or `pub(...)` off `output`
+fn take_visibility(output: &mut Vec<TokenTree>) -> Vec<TokenTree> {
+    let len = output.len();
+    let restricted = matches!(
+        output.last(),
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Parenthesis
+    ) && len >= 2
+        && is_ident(output.get(len - 2), "pub");
+    if restricted {
+        output.split_off(len - 2)
+    } else if is_ident(output.last(), "pub") {
+        output.split_off(len - 1)
+    } else {
+        Vec::new()
+    }
+}
+
+// Pop trailing `#[...]` attributes off `output`
+fn take_outer_attributes(output: &mut Vec<TokenTree>) -> Vec<TokenTree> {
+    let mut start = output.len();
+    while start >= 2
+        && is_punct(output.get(start - 2), '#')
+        && matches!(
+            output.get(start - 1),
+            Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Bracket
+        )
+    {
+        start -= 2;
+    }
+    output.split_off(start)
+}
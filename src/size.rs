@@ -0,0 +1,163 @@
+// Size accounting for `--max-bytes` and `--max-tokens`.
+//
+// Tokens are counted the way the lexer sees them: whitespace and comments are
+// free, doc comments included, and a delimited group counts one token for each
+// of its delimiters. When a limit is exceeded the output is broken down by the
+// file each token came from, so it's clear what to trim.
+
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use proc_macro2::{Delimiter, TokenStream, TokenTree};
+
+use crate::annotate::{Annotations, GENERATED};
+use crate::minify::is_doc_attribute;
+use crate::ModuleInfo;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSize {
+    pub path: String,
+    pub bytes: usize,
+    pub tokens: usize,
+}
+
+pub fn count_tokens(code: &str) -> Result<usize> {
+    let tokens = TokenStream::from_str(code)
+        .map_err(|err| anyhow::anyhow!("{}", err))
+        .context("Failed to tokenize the merged code")?;
+    let mut tally = Tally::new(None);
+    tally.walk(tokens, 0);
+    Ok(tally.files[0].tokens)
+}
+
+// How much of `output` comes from each file of the merged crate `content`, largest
+// first. Tokens are counted before formatting, which may add the odd trailing
+// comma; the bytes of the output are shared out in proportion to the length of
+// each file's tokens, since formatting adds its own whitespace.
+pub fn file_sizes(content: &TokenStream, annotations: &Annotations, output: &str) -> Vec<FileSize> {
+    let mut tally = Tally::new(Some(annotations));
+    tally.walk(content.clone(), 0);
+
+    let total_length: usize = tally.lengths.iter().sum();
+    let mut files = tally.files;
+    for (file, length) in files.iter_mut().zip(tally.lengths) {
+        file.bytes = (output.len() * length)
+            .checked_div(total_length)
+            .unwrap_or(0);
+    }
+    sorted(files)
+}
+
+// With `--preserve-comments` every file is copied into the output as is
+pub fn source_file_sizes(module_structure: &HashMap<String, ModuleInfo>) -> Result<Vec<FileSize>> {
+    let mut files: Vec<FileSize> = Vec::new();
+    for module_info in module_structure.values() {
+        let path = module_info
+            .rel_path
+            .clone()
+            .unwrap_or_else(|| module_info.file_path.to_string_lossy().to_string());
+        if files.iter().any(|file| file.path == path) {
+            continue;
+        }
+        let source = fs::read_to_string(&module_info.file_path)
+            .with_context(|| format!("Failed to read {:?}", module_info.file_path))?;
+        files.push(FileSize {
+            path,
+            bytes: source.len(),
+            tokens: count_tokens(&source)?,
+        });
+    }
+    Ok(sorted(files))
+}
+
+fn sorted(mut files: Vec<FileSize>) -> Vec<FileSize> {
+    files.retain(|file| file.tokens > 0);
+    files.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.path.cmp(&b.path)));
+    files
+}
+
+struct Tally<'a> {
+    annotations: Option<&'a Annotations>,
+    files: Vec<FileSize>,
+    lengths: Vec<usize>, // Length of each file's tokens printed without spaces
+}
+
+impl<'a> Tally<'a> {
+    fn new(annotations: Option<&'a Annotations>) -> Self {
+        Tally {
+            annotations,
+            files: vec![FileSize {
+                path: GENERATED.to_string(),
+                bytes: 0,
+                tokens: 0,
+            }],
+            lengths: vec![0],
+        }
+    }
+
+    // A file comment placeholder switches to its file until the end of the group
+    fn walk(&mut self, tokens: TokenStream, mut file: usize) {
+        let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+        let mut i = 0;
+        while i < tokens.len() {
+            if let Some(annotation) = self
+                .annotations
+                .and_then(|annotations| annotations.marked(&tokens[i..]))
+            {
+                file = self.file(&annotation.path);
+                i += 4;
+                continue;
+            }
+            match &tokens[i..] {
+                [TokenTree::Punct(pound), TokenTree::Group(group), ..]
+                    if pound.as_char() == '#' && is_doc_attribute(group) =>
+                {
+                    self.count(file, 0, group.to_string().len());
+                    i += 2;
+                    continue;
+                }
+                [TokenTree::Punct(pound), TokenTree::Punct(bang), TokenTree::Group(group), ..]
+                    if pound.as_char() == '#'
+                        && bang.as_char() == '!'
+                        && is_doc_attribute(group) =>
+                {
+                    self.count(file, 0, group.to_string().len());
+                    i += 3;
+                    continue;
+                }
+                _ => {}
+            }
+
+            match &tokens[i] {
+                TokenTree::Group(group) => {
+                    if group.delimiter() != Delimiter::None {
+                        self.count(file, 2, 2);
+                    }
+                    self.walk(group.stream(), file);
+                }
+                token => self.count(file, 1, token.to_string().len()),
+            }
+            i += 1;
+        }
+    }
+
+    fn file(&mut self, path: &str) -> usize {
+        if let Some(index) = self.files.iter().position(|file| file.path == path) {
+            return index;
+        }
+        self.files.push(FileSize {
+            path: path.to_string(),
+            bytes: 0,
+            tokens: 0,
+        });
+        self.lengths.push(0);
+        self.files.len() - 1
+    }
+
+    fn count(&mut self, file: usize, tokens: usize, length: usize) {
+        self.files[file].tokens += tokens;
+        self.lengths[file] += length;
+    }
+}
@@ -0,0 +1,135 @@
+// `--source-map`: a JSON file next to the merged output that says where each of
+// its lines comes from, so that compiler errors, review comments and search hits
+// on the merged file can be taken back to the original tree.
+//
+//     {
+//       "file": "app.rs",
+//       "sources": {
+//         "main.rs": "/home/me/app/src/main.rs"
+//       },
+//       "mappings": [
+//         { "lines": [1, 4], "source": "main.rs", "line": 1, "column": 1, "end_line": 4 }
+//       ]
+//     }
+//
+// A mapping takes a run of output lines back to the run of source lines from
+// `line` to `end_line`. When the two are as long, they match line for line;
+// otherwise formatting has split or joined lines, or dropped blank ones.
+// `column` is where the code on the first source line starts. Blank lines
+// aren't mapped. All lines and columns count from 1.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::annotate::Location;
+use crate::manifest::absolute;
+
+#[derive(Debug)]
+struct Mapping<'a> {
+    lines: (usize, usize), // First and last output line
+    location: &'a Location,
+    end_line: usize, // Last source line
+}
+
+// `locations` has an entry per line of the code, which starts on line
+// `line_offset + 1` of `file_name`
+pub fn source_map(file_name: &str, locations: &[Option<Location>], line_offset: usize) -> String {
+    let mut mappings: Vec<Mapping> = Vec::new();
+    for (index, location) in locations.iter().enumerate() {
+        let Some(location) = location else {
+            continue;
+        };
+        let line = index + 1 + line_offset;
+        match mappings.last_mut() {
+            // Blank lines in between don't break a run
+            Some(mapping)
+                if mapping.location.file == location.file
+                    && (mapping.end_line..=mapping.end_line + line - mapping.lines.1)
+                        .contains(&location.line) =>
+            {
+                mapping.lines.1 = line;
+                mapping.end_line = location.line;
+            }
+            _ => mappings.push(Mapping {
+                lines: (line, line),
+                location,
+                end_line: location.line,
+            }),
+        }
+    }
+
+    // Files outside the src directory go by their name alone, which another file
+    // can share, so later ones get a `#2`, `#3`, ... suffix
+    let mut sources: Vec<(String, &Path)> = Vec::new();
+    for mapping in &mappings {
+        let file = mapping.location.file.as_path();
+        if sources.iter().any(|(_, source)| *source == file) {
+            continue;
+        }
+        let mut key = mapping.location.path.clone();
+        let mut n = 1;
+        while sources.iter().any(|(source_key, _)| *source_key == key) {
+            n += 1;
+            key = format!("{}#{}", mapping.location.path, n);
+        }
+        sources.push((key, file));
+    }
+    let source_key = |file: &Path| {
+        sources
+            .iter()
+            .find(|(_, source)| *source == file)
+            .map_or("", |(key, _)| key.as_str())
+    };
+
+    let mut json = String::new();
+    writeln!(json, "{{").unwrap();
+    writeln!(json, "  \"file\": {},", json_string(file_name)).unwrap();
+    writeln!(json, "  \"sources\": {{").unwrap();
+    for (i, (key, file)) in sources.iter().enumerate() {
+        let comma = if i + 1 < sources.len() { "," } else { "" };
+        writeln!(
+            json,
+            "    {}: {}{}",
+            json_string(key),
+            json_string(&absolute(file).to_string_lossy()),
+            comma
+        )
+        .unwrap();
+    }
+    writeln!(json, "  }},").unwrap();
+    writeln!(json, "  \"mappings\": [").unwrap();
+    for (i, mapping) in mappings.iter().enumerate() {
+        let comma = if i + 1 < mappings.len() { "," } else { "" };
+        writeln!(
+            json,
+            "    {{ \"lines\": [{}, {}], \"source\": {}, \"line\": {}, \"column\": {}, \"end_line\": {} }}{}",
+            mapping.lines.0,
+            mapping.lines.1,
+            json_string(source_key(&mapping.location.file)),
+            mapping.location.line,
+            mapping.location.column,
+            mapping.end_line,
+            comma
+        )
+        .unwrap();
+    }
+    writeln!(json, "  ]").unwrap();
+    writeln!(json, "}}").unwrap();
+    json
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,324 @@
+// Comment-preserving merge. Instead of round-tripping through `TokenStream`, the
+// original text of every file is kept byte-for-byte and only the `mod foo;`
+// declarations are rewritten into `mod foo { <text of foo.rs> }`. Span locations
+// from syn tell us where each declaration sits in the source.
+
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use quote::ToTokens;
+use syn::spanned::Spanned;
+use syn::{AttrStyle, Item};
+
+use crate::annotate::{file_comment, item_comment, LineMap, Region};
+use crate::resolver::{path_attr, ModuleDir};
+use crate::{is_ignored_item, module_attrs};
+
+#[derive(Debug)]
+struct Edit {
+    range: Range<usize>,  // Byte range in the source text
+    text: String,         // Replacement text
+    regions: Vec<Region>, // Where the lines of the text come from, from its line 1
+}
+
+impl Edit {
+    fn new(range: Range<usize>, text: String) -> Self {
+        Edit {
+            range,
+            text,
+            regions: Vec::new(),
+        }
+    }
+}
+
+// A spliced file: its shebang line, if any, and its text, with the regions of
+// the text's lines
+struct Spliced {
+    shebang: Option<String>,
+    text: String,
+    regions: Vec<Region>,
+}
+
+// With `annotate_items`, every item gets a `// path:first-last` comment
+pub fn merge_source_text(
+    root_file: &Path,
+    src_dir: &Path,
+    annotate_items: bool,
+) -> Result<(String, LineMap)> {
+    let spliced = splice_file(
+        root_file,
+        src_dir,
+        &ModuleDir::crate_root(root_file),
+        annotate_items,
+    )?;
+
+    let mut merged = String::new();
+    if let Some(shebang) = spliced.shebang {
+        merged.push_str(&shebang);
+        merged.push('\n');
+    }
+    let path = display_path(root_file, src_dir);
+    merged.push_str(&format!("// {}\n", path));
+    let mut regions = vec![Region {
+        output_line: merged.matches('\n').count(),
+        path,
+        file: root_file.to_path_buf(),
+        first: 1,
+        last: None,
+        verbatim: true,
+    }];
+    regions.extend(push_text(&mut merged, &spliced.text, spliced.regions));
+    Ok((merged, LineMap::new(regions)))
+}
+
+// Append `text` without its leading newline, and its regions moved to where it lands
+fn push_text(output: &mut String, text: &str, regions: Vec<Region>) -> Vec<Region> {
+    let stripped = text.strip_prefix('\n');
+    let offset = output.matches('\n').count();
+    output.push_str(stripped.unwrap_or(text));
+    regions
+        .into_iter()
+        .map(|region| Region {
+            // A region that started on the stripped line starts on the next one
+            output_line: (region.output_line + offset - usize::from(stripped.is_some()))
+                .max(offset + 1),
+            ..region
+        })
+        .collect()
+}
+
+fn splice_file(
+    file_path: &Path,
+    src_dir: &Path,
+    module_dir: &ModuleDir,
+    annotate_items: bool,
+) -> Result<Spliced> {
+    let source = fs::read_to_string(file_path)
+        .with_context(|| format!("Failed to read module file {:?}", file_path))?;
+    let file = syn::parse_file(&source)?;
+    let body = &source[span_offset(&source, &file)..];
+
+    let path = display_path(file_path, src_dir);
+    let item_comments = annotate_items.then_some(path.as_str());
+    let mut edits = Vec::new();
+    collect_edits(
+        &file.items,
+        body,
+        src_dir,
+        module_dir,
+        item_comments,
+        &mut edits,
+    )?;
+
+    let origin = Region {
+        output_line: 1,
+        path: path.clone(),
+        file: PathBuf::from(file_path),
+        first: 1,
+        last: None,
+        verbatim: true,
+    };
+    let (text, regions) = apply_edits(body, edits, &origin);
+    Ok(Spliced {
+        shebang: file.shebang,
+        text,
+        regions,
+    })
+}
+
+// `item_comments` is the path to put in item comments, if items get them
+fn collect_edits(
+    items: &[Item],
+    source: &str,
+    src_dir: &Path,
+    module_dir: &ModuleDir,
+    item_comments: Option<&str>,
+    edits: &mut Vec<Edit>,
+) -> Result<()> {
+    for item in items {
+        if is_ignored_item(item) {
+            edits.push(Edit::new(
+                whole_lines(source, item.span().byte_range()),
+                String::new(),
+            ));
+            continue;
+        }
+
+        // Comments go on a line of their own, like those of the token-based merge
+        let start = item.span().byte_range().start;
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let indent = &source[line_start..start];
+        if let Some(path) = item_comments.filter(|_| !matches!(item, Item::Use(_))) {
+            if indent.trim().is_empty() {
+                let comment = item_comment(path, item.span().start().line, item.span().end().line);
+                edits.push(Edit::new(
+                    line_start..line_start,
+                    format!("{}{}\n", indent, comment),
+                ));
+            }
+        }
+
+        let Item::Mod(item_mod) = item else {
+            continue;
+        };
+
+        // Rewrite the attributes that only served to locate the module file
+        for attr in &item_mod.attrs {
+            if !matches!(attr.style, AttrStyle::Outer) {
+                continue;
+            }
+            let original = attr.to_token_stream().to_string();
+            match module_attrs(std::slice::from_ref(attr)).first() {
+                Some(kept) if kept.to_token_stream().to_string() == original => {}
+                Some(kept) => edits.push(Edit::new(
+                    attr.span().byte_range(),
+                    kept.to_token_stream().to_string(),
+                )),
+                None => edits.push(Edit::new(
+                    whole_lines(source, attr.span().byte_range()),
+                    String::new(),
+                )),
+            }
+        }
+
+        match &item_mod.content {
+            Some((_, items)) => collect_edits(
+                items,
+                source,
+                src_dir,
+                &module_dir.inline(&item_mod.ident, &item_mod.attrs),
+                item_comments,
+                edits,
+            )?,
+            None => {
+                let resolved = module_dir.resolve(&item_mod.ident, &item_mod.attrs)?;
+                let spliced = splice_file(
+                    &resolved.file,
+                    src_dir,
+                    &resolved.dir,
+                    item_comments.is_some(),
+                )?;
+                let semi = item_mod
+                    .semi
+                    .context("External module declaration without a semicolon")?;
+
+                let path = display_path(&resolved.file, src_dir);
+                let comment = file_comment(&path, path_attr(&item_mod.attrs).is_some());
+                let mut block = format!(" {{\n{}\n", comment);
+                // The file comment counts as the top of its file
+                let mut regions = vec![Region {
+                    output_line: 2,
+                    path,
+                    file: resolved.file.clone(),
+                    first: 1,
+                    last: None,
+                    verbatim: true,
+                }];
+                regions.extend(push_text(&mut block, &spliced.text, spliced.regions));
+                if !block.ends_with('\n') {
+                    block.push('\n');
+                }
+                block.push('}');
+                edits.push(Edit {
+                    range: semi.span.byte_range(),
+                    text: block,
+                    regions,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The edited text, and where its lines come from: the text kept between edits
+// follows `origin`, the file `source` is the text of
+fn apply_edits(source: &str, mut edits: Vec<Edit>, origin: &Region) -> (String, Vec<Region>) {
+    edits.sort_by_key(|edit| edit.range.start);
+
+    let mut result = String::with_capacity(source.len());
+    let mut regions = Vec::new();
+    let mut position = 0;
+    for edit in edits {
+        keep_source(
+            &mut result,
+            &mut regions,
+            source,
+            position..edit.range.start,
+            origin,
+        );
+        let offset = result.matches('\n').count();
+        regions.extend(edit.regions.into_iter().map(|region| Region {
+            output_line: region.output_line + offset,
+            ..region
+        }));
+        result.push_str(&edit.text);
+        position = edit.range.end;
+    }
+    keep_source(
+        &mut result,
+        &mut regions,
+        source,
+        position..source.len(),
+        origin,
+    );
+    (result, regions)
+}
+
+// Copy a range of the source into the result, as a region of its own
+fn keep_source(
+    result: &mut String,
+    regions: &mut Vec<Region>,
+    source: &str,
+    range: Range<usize>,
+    origin: &Region,
+) {
+    if !range.is_empty() {
+        regions.push(Region {
+            output_line: result.matches('\n').count() + 1,
+            first: origin.first + source[..range.start].matches('\n').count(),
+            ..origin.clone()
+        });
+    }
+    result.push_str(&source[range]);
+}
+
+// Widen a range that is alone on its lines to cover those whole lines, so that
+// removing it doesn't leave blank lines behind
+fn whole_lines(source: &str, range: Range<usize>) -> Range<usize> {
+    let line_start = source[..range.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[range.end..]
+        .find('\n')
+        .map_or(source.len(), |i| range.end + i + 1);
+
+    if source[line_start..range.start].trim().is_empty()
+        && source[range.end..line_end].trim().is_empty()
+    {
+        line_start..line_end
+    } else {
+        range
+    }
+}
+
+// `syn::parse_file` strips the BOM and shebang before lexing, so spans are
+// relative to the text that follows them; this is where that text starts
+pub fn span_offset(source: &str, file: &syn::File) -> usize {
+    let bom_len = if source.starts_with('\u{feff}') {
+        '\u{feff}'.len_utf8()
+    } else {
+        0
+    };
+    bom_len + file.shebang.as_ref().map_or(0, |shebang| shebang.len())
+}
+
+fn display_path(file_path: &Path, src_dir: &Path) -> String {
+    file_path
+        .strip_prefix(src_dir)
+        .ok()
+        .or_else(|| file_path.file_name().map(Path::new))
+        .unwrap_or(file_path)
+        .to_string_lossy()
+        .to_string()
+}
@@ -0,0 +1,208 @@
+// Merged code that builds on its own, away from the package it came from: either
+// as a script with the manifest embedded, that runs as is (`---cargo` front matter
+// for `cargo +nightly -Zscript`, a `//! ```cargo` doc block for rust-script), or
+// as a crate of its own with a generated Cargo.toml.
+//
+// Only what a single-target crate can use is carried over: the edition,
+// `[dependencies]` and `[features]`. Path dependencies point at their absolute
+// location, unless they were bundled into the file, in which case they are
+// dropped along with the features that refer to them.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use regex::Regex;
+
+use crate::manifest::{Manifest, Target, TargetKind};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScriptKind {
+    Cargo,      // `---cargo` front matter
+    RustScript, // `//! ```cargo` doc comment
+}
+
+pub struct StandaloneManifest {
+    package_name: String,
+    version: String,
+    edition: String,
+    dependencies: Vec<(String, toml::Table)>,
+    features: Vec<(String, toml::Value)>,
+    depends_on_package: bool, // Whether the package itself is one of the dependencies
+}
+
+impl StandaloneManifest {
+    // `bundle_deps` and `inline_lib` are whether the path dependencies and the
+    // package's library were merged into the file
+    pub fn new(manifest: &Manifest, target: &Target, bundle_deps: bool, inline_lib: bool) -> Self {
+        let (bundled, mut dependencies): (Vec<_>, Vec<_>) =
+            manifest.dependencies().into_iter().partition(|(_, table)| {
+                bundle_deps
+                    && table.contains_key("path")
+                    && table.get("optional").and_then(|o| o.as_bool()) != Some(true)
+            });
+        let bundled: HashSet<String> = bundled.into_iter().map(|(key, _)| key).collect();
+
+        let features = manifest
+            .features()
+            .into_iter()
+            .flatten()
+            .map(|(name, enables)| {
+                let enables = enables
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter(|feature| {
+                        feature
+                            .as_str()
+                            .is_none_or(|feature| !bundled.contains(feature_dependency(feature)))
+                    })
+                    .cloned()
+                    .collect();
+                (name.clone(), toml::Value::Array(enables))
+            })
+            .collect();
+
+        let package_name = manifest.package_name().unwrap_or("merged").to_string();
+        // A binary that uses its package's library without bundling it needs it as a dependency
+        let depends_on_package =
+            target.kind == TargetKind::Bin && !inline_lib && manifest.lib_target().is_some();
+        if depends_on_package {
+            dependencies.push((package_name.clone(), manifest.path_dependency_on_self()));
+        }
+
+        StandaloneManifest {
+            package_name,
+            version: manifest
+                .package_field("version")
+                .and_then(|version| version.as_str())
+                .unwrap_or("0.1.0")
+                .to_string(),
+            edition: manifest.edition(),
+            dependencies,
+            features,
+            depends_on_package,
+        }
+    }
+
+    // `code` with the manifest in front, replacing any shebang line it has
+    pub fn embed(&self, code: &str, kind: ScriptKind) -> String {
+        let code = strip_shebang(code);
+        let mut toml = String::new();
+        writeln!(toml, "[package]").unwrap();
+        writeln!(
+            toml,
+            "edition = {}",
+            toml::Value::from(self.edition.as_str())
+        )
+        .unwrap();
+        self.write_dependencies(&mut toml);
+
+        match kind {
+            ScriptKind::Cargo => format!(
+                "#!/usr/bin/env -S cargo +nightly -Zscript\n---cargo\n{}---\n\n{}",
+                toml, code
+            ),
+            ScriptKind::RustScript => {
+                let mut script = String::from("#!/usr/bin/env rust-script\n//! ```cargo\n");
+                for line in toml.lines() {
+                    script.push_str(format!("//! {}", line).trim_end());
+                    script.push('\n');
+                }
+                script.push_str("//! ```\n\n");
+                script.push_str(code);
+                script
+            }
+        }
+    }
+
+    // Cargo.toml of a crate made of the merged `target` alone, in `root_file`
+    pub fn cargo_toml(&self, target: &Target) -> String {
+        // Two packages with the same name can't be in one build
+        let package_name = if self.depends_on_package {
+            format!("{}-merged", self.package_name)
+        } else {
+            self.package_name.clone()
+        };
+
+        let mut toml = String::new();
+        writeln!(toml, "[package]").unwrap();
+        writeln!(toml, "name = {}", toml::Value::from(package_name)).unwrap();
+        writeln!(
+            toml,
+            "version = {}",
+            toml::Value::from(self.version.as_str())
+        )
+        .unwrap();
+        writeln!(
+            toml,
+            "edition = {}",
+            toml::Value::from(self.edition.as_str())
+        )
+        .unwrap();
+        let section = match target.kind {
+            TargetKind::Lib => "[lib]",
+            TargetKind::Bin => "[[bin]]",
+        };
+        writeln!(toml, "\n{}", section).unwrap();
+        writeln!(toml, "name = {}", toml::Value::from(target.name.as_str())).unwrap();
+        writeln!(toml, "path = {}", toml::Value::from(root_file(target))).unwrap();
+        self.write_dependencies(&mut toml);
+        // Keep the crate out of any workspace it's written into
+        writeln!(toml, "\n[workspace]").unwrap();
+        toml
+    }
+
+    fn write_dependencies(&self, toml: &mut String) {
+        if !self.dependencies.is_empty() {
+            writeln!(toml, "\n[dependencies]").unwrap();
+            for (key, table) in &self.dependencies {
+                let value = match table.get("version") {
+                    Some(version) if table.len() == 1 => version.clone(),
+                    _ => toml::Value::Table(table.clone()),
+                };
+                writeln!(toml, "{} = {}", toml_key(key), value).unwrap();
+            }
+        }
+        if !self.features.is_empty() {
+            writeln!(toml, "\n[features]").unwrap();
+            for (name, enables) in &self.features {
+                writeln!(toml, "{} = {}", toml_key(name), enables).unwrap();
+            }
+        }
+    }
+}
+
+// Where the merged target goes in a crate of its own
+pub fn root_file(target: &Target) -> &'static str {
+    match target.kind {
+        TargetKind::Lib => "src/lib.rs",
+        TargetKind::Bin => "src/main.rs",
+    }
+}
+
+// The dependency a feature entry like `dep:serde`, `serde/std` or `serde?/std`
+// refers to, or the feature itself
+fn feature_dependency(feature: &str) -> &str {
+    let feature = feature.strip_prefix("dep:").unwrap_or(feature);
+    let feature = feature.split('/').next().unwrap_or(feature);
+    feature.strip_suffix('?').unwrap_or(feature)
+}
+
+fn toml_key(key: &str) -> String {
+    let bare = Regex::new(r"^[A-Za-z0-9_-]+$").unwrap();
+    if bare.is_match(key) {
+        key.to_string()
+    } else {
+        toml::Value::from(key).to_string()
+    }
+}
+
+// `#!/usr/bin/env ...`, but not an inner attribute `#![...]`
+fn strip_shebang(code: &str) -> &str {
+    match code.strip_prefix("#!") {
+        Some(rest) if !rest.trim_start().starts_with('[') => {
+            code.split_once('\n').map_or("", |(_, rest)| rest)
+        }
+        _ => code,
+    }
+}
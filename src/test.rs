@@ -4,7 +4,7 @@ mod tests {
     use std::fs::File;
     use std::io::Write;
     use std::path::PathBuf;
-    use syn::{parse_quote, Item};
+    use syn::{parse_quote, AttrStyle, Item, ItemMod};
     use tempfile::TempDir;
 
     fn setup_temp_cargo_toml(package_name: &str) -> (TempDir, PathBuf) {
@@ -24,6 +24,40 @@ edition = "2021"
         (temp_dir, cargo_toml_path)
     }
 
+    // Parse the crate rooted at `src/lib.rs`, or `src/main.rs` when there is no library
+    fn parse_module_structure(src_dir: &Path) -> Result<HashMap<String, ModuleInfo>> {
+        let lib_rs = src_dir.join("lib.rs");
+        let root_file = if lib_rs.exists() {
+            lib_rs
+        } else {
+            src_dir.join("main.rs")
+        };
+        parse_crate(&root_file, src_dir)
+    }
+
+    // Merge the parsed modules into formatted source with file comments rendered
+    fn merge_package(
+        src_dir: &Path,
+        module_structure: &HashMap<String, ModuleInfo>,
+        embedded: &[EmbeddedCrate],
+        format_options: &FormatOptions,
+    ) -> Result<String> {
+        let (merged_content, annotations) =
+            merge_tokens(src_dir, module_structure, embedded, ItemAnnotations::None)?;
+        render_merged(&merged_content, &annotations, format_options).map(|(code, _)| code)
+    }
+
+    // Build a merged binary with rustc, next to the file
+    fn assert_compiles(merged_file: &Path) -> Result<()> {
+        let status = Command::new("rustc")
+            .args(["--edition=2021", "--crate-type=bin", "-o"])
+            .arg(merged_file.with_extension("bin"))
+            .arg(merged_file)
+            .status()?;
+        assert!(status.success(), "{:?} doesn't compile", merged_file);
+        Ok(())
+    }
+
     #[test]
     fn test_determine_package_with_provided_name() {
         let package_name = "test_package".to_string();
@@ -46,57 +80,1359 @@ edition = "2021"
     }
 
     #[test]
-    fn test_find_src_dir() {
-        // Create a temporary directory using tempfile crate
-        let temp_dir = tempfile::tempdir().unwrap();
-
-        // Create "src" directory inside the temp directory
+    fn test_find_src_dir() {
+        // Create a temporary directory using tempfile crate
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        // Create "src" directory inside the temp directory
+        let src_dir = temp_dir.path().join("src");
+        std::fs::create_dir_all(&src_dir).unwrap();
+
+        // Create a dummy Cargo.toml file inside the temp directory
+        let cargo_toml_path = temp_dir.path().join("Cargo.toml");
+        let mut cargo_toml = File::create(&cargo_toml_path).unwrap();
+        writeln!(
+            cargo_toml,
+            r#"[package]
+    name = "test_package"
+    version = "0.1.0"
+    edition = "2021"
+    "#,
+        )
+        .unwrap();
+
+        // Test the find_src_dir function
+        let src_dir_found = find_src_dir(temp_dir.path()).expect("Failed to find src dir");
+        assert_eq!(src_dir_found, src_dir);
+    }
+
+    #[test]
+    fn test_parse_module_structure() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("src")).unwrap();
+
+        let lib_rs_path = temp_dir.path().join("src/lib.rs");
+        let mut lib_rs = File::create(&lib_rs_path).unwrap();
+        writeln!(lib_rs, "pub mod example_module;").unwrap();
+
+        let mod_path = temp_dir.path().join("src/example_module.rs");
+        let mut mod_file = File::create(&mod_path).unwrap();
+        writeln!(mod_file, "pub fn example_function() -> i32 {{ 42 }}").unwrap();
+
+        let module_structure = parse_module_structure(&temp_dir.path().join("src"))
+            .expect("Failed to parse module structure");
+
+        assert!(module_structure.contains_key("crate"));
+        assert!(module_structure.contains_key("example_module"));
+    }
+
+    #[test]
+    fn test_format_rust_code() {
+        let code = r#"fn main() {println!("Hello, world!");}"#;
+        let formatted_code =
+            format_rust_code(code, &FormatOptions::default()).expect("Failed to format code");
+        assert!(formatted_code.contains("fn main() {"));
+
+        // Code rustfmt can't parse fails with its diagnostics
+        let err = format_rust_code("fn main() {", &FormatOptions::default()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("this file contains an unclosed delimiter"));
+    }
+
+    #[test]
+    fn test_format_code_with_each_formatter() -> Result<()> {
+        let code = r#"mod a { #![allow(unused)] fn main() {println!("Hello, world!");} }"#;
+
+        let builtin = format_code(
+            code,
+            &FormatOptions {
+                formatter: Formatter::Builtin,
+                ..FormatOptions::default()
+            },
+        )?;
+        assert!(builtin.contains("mod a {\n    #![allow(unused)]\n    fn main() {\n"));
+
+        let rustfmt = format_code(
+            code,
+            &FormatOptions {
+                formatter: Formatter::Rustfmt,
+                ..FormatOptions::default()
+            },
+        )?;
+        assert!(rustfmt.contains("fn main() {"));
+
+        let none = format_code(
+            code,
+            &FormatOptions {
+                formatter: Formatter::None,
+                ..FormatOptions::default()
+            },
+        )?;
+        assert_eq!(none, code);
+        Ok(())
+    }
+
+    #[test]
+    fn test_builtin_formatter_renders_file_comments() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(&src_dir)?;
+        fs::write(src_dir.join("lib.rs"), "mod inner;")?;
+        fs::write(src_dir.join("inner.rs"), "pub fn inner_fn() {}")?;
+
+        let module_structure = parse_module_structure(&src_dir)?;
+        let formatted_code = merge_package(
+            &src_dir,
+            &module_structure,
+            &[],
+            &FormatOptions {
+                formatter: Formatter::Builtin,
+                ..FormatOptions::default()
+            },
+        )?;
+
+        assert_eq!(
+            formatted_code,
+            "// lib.rs\nmod inner {\n    // inner.rs\n    pub fn inner_fn() {}\n}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_edition() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        fs::write(
+            temp_dir.path().join("Cargo.toml"),
+            r#"
+[workspace]
+members = ["inherits", "explicit", "default"]
+
+[workspace.package]
+edition = "2018"
+"#,
+        )?;
+        for (member, edition) in [
+            ("inherits", "edition.workspace = true"),
+            ("explicit", "edition = \"2024\""),
+            ("default", ""),
+        ] {
+            fs::create_dir_all(temp_dir.path().join(member))?;
+            fs::write(
+                temp_dir.path().join(member).join("Cargo.toml"),
+                format!(
+                    "[package]\nname = \"{}\"\nversion = \"0.1.0\"\n{}\n",
+                    member, edition
+                ),
+            )?;
+        }
+
+        assert_eq!(
+            Manifest::load(&temp_dir.path().join("inherits"))?.edition(),
+            "2018"
+        );
+        assert_eq!(
+            Manifest::load(&temp_dir.path().join("explicit"))?.edition(),
+            "2024"
+        );
+        assert_eq!(
+            Manifest::load(&temp_dir.path().join("default"))?.edition(),
+            "2015"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_with_package_rustfmt_config() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let package_dir = temp_dir.path().join("package");
+        fs::create_dir_all(&package_dir)?;
+        fs::write(temp_dir.path().join(".rustfmt.toml"), "tab_spaces = 2\n")?;
+
+        let config = find_rustfmt_config(&package_dir);
+        assert_eq!(
+            config.as_deref().and_then(|path| path.file_name()),
+            Some(".rustfmt.toml".as_ref())
+        );
+
+        let formatted = format_rust_code(
+            "mod a { fn f() {} }",
+            &FormatOptions {
+                formatter: Formatter::Rustfmt,
+                edition: "2018".to_string(),
+                rustfmt_config: config,
+            },
+        )?;
+        assert_eq!(formatted, "mod a {\n  fn f() {}\n}\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_targets() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let package_dir = temp_dir.path();
+        fs::write(
+            package_dir.join("Cargo.toml"),
+            r#"
+[package]
+name = "my-tools"
+version = "0.1.0"
+
+[lib]
+path = "lib/mod.rs"
+
+[[bin]]
+name = "tool"
+path = "tools/tool.rs"
+"#,
+        )?;
+        fs::create_dir_all(package_dir.join("lib"))?;
+        fs::create_dir_all(package_dir.join("tools"))?;
+        fs::create_dir_all(package_dir.join("src/bin/multi"))?;
+        fs::write(package_dir.join("lib/mod.rs"), "")?;
+        fs::write(package_dir.join("tools/tool.rs"), "fn main() {}")?;
+        fs::write(package_dir.join("src/main.rs"), "fn main() {}")?;
+        fs::write(package_dir.join("src/bin/extra.rs"), "fn main() {}")?;
+        fs::write(package_dir.join("src/bin/multi/main.rs"), "fn main() {}")?;
+
+        let manifest = Manifest::load(package_dir)?;
+        let lib = manifest.lib_target().unwrap();
+        assert_eq!(lib.name, "my_tools");
+        assert_eq!(lib.path, package_dir.join("lib/mod.rs"));
+
+        let bins: Vec<_> = manifest
+            .bin_targets()
+            .into_iter()
+            .map(|bin| (bin.name, bin.path))
+            .collect();
+        assert_eq!(
+            bins,
+            vec![
+                ("extra".to_string(), package_dir.join("src/bin/extra.rs")),
+                (
+                    "multi".to_string(),
+                    package_dir.join("src/bin/multi/main.rs")
+                ),
+                ("my-tools".to_string(), package_dir.join("src/main.rs")),
+                ("tool".to_string(), package_dir.join("tools/tool.rs")),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_target() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let package_dir = temp_dir.path();
+        fs::write(
+            package_dir.join("Cargo.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\n",
+        )?;
+        fs::create_dir_all(package_dir.join("src/bin"))?;
+        fs::write(package_dir.join("src/bin/one.rs"), "fn main() {}")?;
+        fs::write(package_dir.join("src/bin/two.rs"), "fn main() {}")?;
+
+        let manifest = Manifest::load(package_dir)?;
+        let error = select_target(&manifest, None, false)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("--bin <name>: one, two"), "{}", error);
+        assert!(select_target(&manifest, Some(&TargetSelection::Lib), false).is_err());
+        assert!(select_target(
+            &manifest,
+            Some(&TargetSelection::Bin("three".into())),
+            false
+        )
+        .is_err());
+
+        let two = select_target(&manifest, Some(&TargetSelection::Bin("two".into())), false)?;
+        assert_eq!(two.path, package_dir.join("src/bin/two.rs"));
+
+        // With a library present, it is merged by default
+        fs::write(package_dir.join("src/lib.rs"), "")?;
+        let manifest = Manifest::load(package_dir)?;
+        assert_eq!(select_target(&manifest, None, false)?.kind, TargetKind::Lib);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_custom_lib_path() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let package_dir = temp_dir.path();
+        fs::write(
+            package_dir.join("Cargo.toml"),
+            "[package]\nname = \"custom\"\nversion = \"0.1.0\"\n\n[lib]\npath = \"lib/mod.rs\"\n",
+        )?;
+        fs::create_dir_all(package_dir.join("lib"))?;
+        fs::write(package_dir.join("lib/mod.rs"), "mod foo;")?;
+        fs::write(package_dir.join("lib/foo.rs"), "pub fn foo() {}")?;
+
+        let target = select_target(&Manifest::load(package_dir)?, None, false)?;
+        let src_dir = target_src_dir(package_dir, &target)?;
+        assert_eq!(src_dir, package_dir.join("lib"));
+
+        let module_structure = parse_crate(&target.path, &src_dir)?;
+        let merged = merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
+        assert!(merged.contains("mod foo {\n    // foo.rs\n    pub fn foo() {}\n}"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_all_targets() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let package_dir = temp_dir.path().join("multi");
+        fs::create_dir_all(package_dir.join("src/bin/helper"))?;
+        fs::write(
+            package_dir.join("Cargo.toml"),
+            "[package]\nname = \"multi\"\nversion = \"0.1.0\"\n",
+        )?;
+        fs::write(package_dir.join("src/lib.rs"), "pub fn lib_fn() {}")?;
+        fs::write(package_dir.join("src/main.rs"), "fn main() {}")?;
+        fs::write(package_dir.join("src/bin/tool.rs"), "fn main() { tool() }")?;
+        fs::write(package_dir.join("src/bin/helper/main.rs"), "mod util;")?;
+        fs::write(package_dir.join("src/bin/helper/util.rs"), "fn util() {}")?;
+
+        let output_dir = temp_dir.path().join("out");
+        let args = Args {
+            output_path: Some(output_dir.clone()),
+            formatter: Formatter::Builtin,
+            target: Some(TargetSelection::AllTargets),
+            ..Args::default()
+        };
+        process_single_package("multi", &package_dir, &args)?;
+
+        let mut written: Vec<_> = fs::read_dir(&output_dir)?
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        written.sort();
+        assert_eq!(written, ["helper.rs", "libmulti.rs", "multi.rs", "tool.rs"]);
+        assert_eq!(
+            fs::read_to_string(output_dir.join("helper.rs"))?,
+            "// bin/helper/main.rs\nmod util {\n    // bin/helper/util.rs\n    fn util() {}\n}\n"
+        );
+        assert!(fs::read_to_string(output_dir.join("libmulti.rs"))?.contains("pub fn lib_fn()"));
+
+        // `--bins` leaves the library out
+        let manifest = Manifest::load(&package_dir)?;
+        let names: Vec<_> = select_targets(&manifest, &TargetSelection::Bins)?
+            .into_iter()
+            .map(|target| target.name)
+            .collect();
+        assert_eq!(names, ["helper", "multi", "tool"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_crate_paths() -> Result<()> {
+        let crates: HashMap<String, String> = [("dep".to_string(), "dep".to_string())].into();
+        let exported_macros: HashSet<String> = ["dep_macro".to_string()].into();
+        let paths = CratePaths::new(&crates, &exported_macros);
+
+        let main: TokenStream = syn::parse_str(
+            r#"
+            #[macro_use]
+            extern crate dep;
+            pub extern crate dep as renamed;
+            use dep::{Thing, dep_macro};
+            use dep::dep_macro;
+            fn f(x: Box<dyn ::dep::Trait>) {
+                dep::run(other::dep::run());
+                dep::dep_macro!();
+            }
+            "#,
+        )?;
+        assert_eq!(
+            paths.rewrite(main, None).to_string(),
+            quote! {
+                pub use crate::dep as renamed;
+                use crate::dep::{Thing};
+                fn f(x: Box<dyn crate::dep::Trait>) {
+                    crate::dep::run(other::dep::run());
+                    crate::dep_macro!();
+                }
+            }
+            .to_string()
+        );
+
+        let dep: TokenStream = syn::parse_str(
+            r#"
+            pub(crate) fn run() { crate::inner::run() }
+            macro_rules! m { () => { $crate::inner::run(); $crate::dep_macro!() } }
+            "#,
+        )?;
+        assert_eq!(
+            paths.rewrite(dep, Some("dep")).to_string(),
+            quote! {
+                pub(crate) fn run() { crate::dep::inner::run() }
+                macro_rules! m { () => { $crate::dep::inner::run(); $crate::dep_macro!() } }
+            }
+            .to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_lib_into_binary() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let package_dir = temp_dir.path().join("my-app");
+        fs::create_dir_all(package_dir.join("src"))?;
+        fs::write(
+            package_dir.join("Cargo.toml"),
+            "[package]\nname = \"my-app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )?;
+        fs::write(
+            package_dir.join("src/lib.rs"),
+            r#"
+            pub mod config;
+            #[macro_export]
+            macro_rules! config { () => { $crate::config::Config::default() } }
+            "#,
+        )?;
+        fs::write(
+            package_dir.join("src/config.rs"),
+            "#[derive(Default)] pub struct Config { pub verbose: bool }",
+        )?;
+        fs::write(
+            package_dir.join("src/main.rs"),
+            r#"
+            use my_app::config::Config;
+            fn main() { let config: Config = my_app::config!(); assert!(!config.verbose); }
+            "#,
+        )?;
+
+        let output_file = temp_dir.path().join("my-app.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            formatter: Formatter::Builtin,
+            inline_lib: true,
+            ..Args::default()
+        };
+        process_single_package("my-app", &package_dir, &args)?;
+
+        let merged = fs::read_to_string(&output_file)?;
+        assert!(merged
+            .starts_with("// main.rs\n#[macro_use]\npub mod my_app {\n    // lib.rs (#[path])\n"));
+        assert!(merged.contains("$crate::my_app::config::Config::default()"));
+        assert!(merged.contains("use crate::my_app::config::Config;"));
+        assert!(merged.contains("crate::config!()"));
+
+        // The bundle is a complete program on its own
+        assert_compiles(&output_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_bundle_path_dependencies() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::write(
+            root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"app\", \"utils\", \"base\"]\n\n[workspace.dependencies]\nbase = { path = \"base\" }\n",
+        )?;
+        for (package, dependencies) in [
+            ("base", ""),
+            ("utils", "base.workspace = true\n"),
+            (
+                "app",
+                "u = { path = \"../utils\", package = \"utils\" }\nbase = { path = \"../base\" }\n",
+            ),
+        ] {
+            fs::create_dir_all(root.join(package).join("src"))?;
+            fs::write(
+                root.join(package).join("Cargo.toml"),
+                format!(
+                    "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n{}",
+                    package, dependencies
+                ),
+            )?;
+        }
+        fs::write(
+            root.join("base/src/lib.rs"),
+            "pub fn id(x: i64) -> i64 { x }\n#[macro_export]\nmacro_rules! twice { ($e:expr) => { $crate::id($e) * 2 } }",
+        )?;
+        fs::write(
+            root.join("utils/src/lib.rs"),
+            "pub use base::id;\npub fn quad(x: i64) -> i64 { crate::double(base::twice!(x)) }\nfn double(x: i64) -> i64 { x * 2 }",
+        )?;
+        fs::write(
+            root.join("app/src/main.rs"),
+            "use base::twice;\nfn main() { assert_eq!(u::quad(1) + twice!(u::id(1)), 6); }",
+        )?;
+
+        let manifest = Manifest::load(&root.join("app"))?;
+        let dependencies: Vec<_> = manifest
+            .path_dependencies()
+            .into_iter()
+            .map(|dependency| dependency.rename)
+            .collect();
+        assert_eq!(dependencies, [None, Some("u".to_string())]);
+
+        let output_file = root.join("app.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            bundle_deps: true,
+            ..Args::default()
+        };
+        process_single_package("app", &root.join("app"), &args)?;
+
+        let merged = fs::read_to_string(&output_file)?;
+        // Dependencies come before their dependents, each one only once
+        let base = merged
+            .find("pub mod base {\n    // base/src/lib.rs (#[path])\n")
+            .unwrap();
+        let utils = merged
+            .find("pub mod utils {\n    // utils/src/lib.rs (#[path])\n")
+            .unwrap();
+        assert!(base < utils);
+        assert_eq!(merged.matches("pub mod base").count(), 1);
+        assert!(merged.contains("$crate::base::id($e) * 2"));
+        assert!(merged.contains("crate::utils::double(crate::twice!(x))"));
+        assert!(merged.contains("crate::utils::quad(1) + twice!(crate::utils::id(1))"));
+
+        assert_compiles(&output_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_shake() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("shaken");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        fs::write(
+            src_dir.join("main.rs"),
+            "mod shapes;\nuse shapes::{area, Square, Unused};\nuse std::fmt::Write as _;\nfn main() { let mut s = String::new(); write!(s, \"{}\", area(&Square(2))).unwrap(); assert_eq!(s, \"4\"); }",
+        )?;
+        fs::write(
+            src_dir.join("shapes.rs"),
+            "pub trait Shape { fn area(&self) -> u32; }\npub struct Square(pub u32);\nimpl Shape for Square { fn area(&self) -> u32 { self.0 * self.0 } }\nimpl Square { pub fn side(&self) -> u32 { self.0 } }\npub fn area(shape: &impl Shape) -> u32 { shape.area() }\npub struct Unused;\nimpl std::fmt::Display for Unused { fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { write!(f, \"unused\") } }\npub fn helper() {}",
+        )?;
+
+        let output_file = temp_dir.path().join("shaken.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            tree_shake: true,
+            ..Args::default()
+        };
+        process_single_package("shaken", temp_dir.path(), &args)?;
+
+        let merged = fs::read_to_string(&output_file)?;
+        assert!(merged.contains("impl Shape for Square"));
+        assert!(merged.contains("use std::fmt::Write as _;"));
+        assert!(!merged.contains("fn side"));
+        assert!(!merged.contains("fn helper"));
+        assert!(!merged.contains("Unused"));
+        assert_compiles(&output_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_cargo_script() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let root = temp_dir.path();
+        fs::write(
+            root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"app\", \"util\"]\n\n[workspace.package]\nedition = \"2021\"\n\n[workspace.dependencies]\nitertools = { version = \"0.13\", default-features = false }\n",
+        )?;
+        fs::create_dir_all(root.join("app/src"))?;
+        fs::create_dir_all(root.join("util/src"))?;
+        fs::write(
+            root.join("app/Cargo.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition.workspace = true\n\n[dependencies]\nitertools = { workspace = true, features = [\"use_std\"] }\nrand = \"0.8\"\nutil = { path = \"../util\" }\n\n[features]\ndefault = [\"fast\"]\nfast = [\"util/fast\", \"rand/small_rng\"]\n",
+        )?;
+        fs::write(
+            root.join("util/Cargo.toml"),
+            "[package]\nname = \"util\"\nversion = \"0.1.0\"\n\n[features]\nfast = []\n",
+        )?;
+        fs::write(root.join("util/src/lib.rs"), "pub fn one() -> u8 { 1 }")?;
+        fs::write(
+            root.join("app/src/main.rs"),
+            "#!/usr/bin/env run-me\nfn main() { assert_eq!(util::one(), 1); }",
+        )?;
+
+        let output_file = root.join("app.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            preserve_comments: true,
+            format: OutputFormat::CargoScript,
+            ..Args::default()
+        };
+        process_single_package("app", &root.join("app"), &args)?;
+        let util_dir = root.join("util").canonicalize()?;
+        assert_eq!(
+            fs::read_to_string(&output_file)?,
+            format!(
+                "#!/usr/bin/env -S cargo +nightly -Zscript\n---cargo\n[package]\nedition = \"2021\"\n\n[dependencies]\nitertools = {{ default-features = false, features = [\"use_std\"], version = \"0.13\" }}\nrand = \"0.8\"\nutil = {{ path = {} }}\n\n[features]\ndefault = [\"fast\"]\nfast = [\"util/fast\", \"rand/small_rng\"]\n---\n\n// main.rs\nfn main() {{ assert_eq!(util::one(), 1); }}",
+                toml::Value::from(util_dir.to_string_lossy().as_ref())
+            )
+        );
+
+        // Bundled dependencies and the features that enable theirs are left out
+        let args = Args {
+            preserve_comments: false,
+            bundle_deps: true,
+            format: OutputFormat::RustScript,
+            ..args
+        };
+        process_single_package("app", &root.join("app"), &args)?;
+        let merged = fs::read_to_string(&output_file)?;
+        assert!(merged.starts_with(
+            "#!/usr/bin/env rust-script\n//! ```cargo\n//! [package]\n//! edition = \"2021\"\n//!\n//! [dependencies]\n//! itertools = { default-features = false, features = [\"use_std\"], version = \"0.13\" }\n//! rand = \"0.8\"\n//!\n//! [features]\n//! default = [\"fast\"]\n//! fast = [\"rand/small_rng\"]\n//! ```\n\n// main.rs\n"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_emit_crate() -> Result<()> {
+        let (temp_dir, cargo_toml) = setup_temp_cargo_toml("tool");
+        fs::write(
+            &cargo_toml,
+            "[package]\nname = \"tool\"\nversion = \"1.2.0\"\nedition = \"2021\"\n\n[dependencies]\nlog = \"0.4\"\n",
+        )?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        fs::write(src_dir.join("lib.rs"), "pub fn run() {}")?;
+        fs::write(src_dir.join("main.rs"), "fn main() { tool::run(); }")?;
+
+        let crate_dir = temp_dir.path().join("standalone");
+        let args = Args {
+            emit_crate: Some(crate_dir.clone()),
+            target: Some(TargetSelection::Bin("tool".to_string())),
+            ..Args::default()
+        };
+        process_single_package("tool", temp_dir.path(), &args)?;
+        // The binary still needs the library, from a package that can't have the same name
+        let package_dir = temp_dir.path().canonicalize()?;
+        assert_eq!(
+            fs::read_to_string(crate_dir.join("Cargo.toml"))?,
+            format!(
+                "[package]\nname = \"tool-merged\"\nversion = \"1.2.0\"\nedition = \"2021\"\n\n[[bin]]\nname = \"tool\"\npath = \"src/main.rs\"\n\n[dependencies]\nlog = \"0.4\"\ntool = {{ path = {} }}\n\n[workspace]\n",
+                toml::Value::from(package_dir.to_string_lossy().as_ref())
+            )
+        );
+        assert!(fs::read_to_string(crate_dir.join("src/main.rs"))?.contains("tool::run();"));
+
+        let args = Args {
+            target: Some(TargetSelection::Lib),
+            ..args
+        };
+        process_single_package("tool", temp_dir.path(), &args)?;
+        assert_eq!(
+            fs::read_to_string(crate_dir.join("Cargo.toml"))?,
+            "[package]\nname = \"tool\"\nversion = \"1.2.0\"\nedition = \"2021\"\n\n[lib]\nname = \"tool\"\npath = \"src/lib.rs\"\n\n[dependencies]\nlog = \"0.4\"\n\n[workspace]\n"
+        );
+        assert!(fs::read_to_string(crate_dir.join("src/lib.rs"))?.contains("pub fn run() {}"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("checked");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        fs::write(
+            src_dir.join("main.rs"),
+            "mod util;\n\nfn main() {\n    util::run();\n}\n",
+        )?;
+        fs::write(
+            src_dir.join("util.rs"),
+            "// Helpers\n\npub fn run() {\n    // Blank lines and comments don't make it into the merged code\n\n    let count: u8 = \"three\";\n    println!(\"{}\", count);\n}\n",
+        )?;
+
+        let manifest = Manifest::load(temp_dir.path())?;
+        let target = select_target(&manifest, None, false)?;
+        let standalone = StandaloneManifest::new(&manifest, &target, false, false);
+        for preserve_comments in [false, true] {
+            let args = Args {
+                preserve_comments,
+                verify: true,
+                ..Args::default()
+            };
+            let merged = merge_target(&manifest, temp_dir.path(), &target, &args)?;
+            let diagnostics =
+                verify::check(&standalone, &target, &merged.code, &merged.lines, None)?;
+            let errors: Vec<_> = diagnostics.iter().filter(|d| d.is_error()).collect();
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0]
+                .message
+                .starts_with("error[E0308]: mismatched types"));
+            let origin = errors[0].origin.as_ref().unwrap();
+            assert_eq!((origin.path.as_str(), origin.line), ("util.rs", 6));
+        }
+
+        let args = Args {
+            output_path: Some(temp_dir.path().join("checked.rs")),
+            verify: true,
+            ..Args::default()
+        };
+        let err = process_single_package("checked", temp_dir.path(), &args).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Merged bin target 'checked' does not compile (errors: 1)"
+        );
+
+        fs::write(
+            src_dir.join("util.rs"),
+            "pub fn run() {\n    println!(\"three\");\n}\n",
+        )?;
+        process_single_package("checked", temp_dir.path(), &args)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_around_modules() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("modules");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        fs::write(
+            src_dir.join("main.rs"),
+            "fn main() {}\n\nstruct helpers;\n\nmod helpers {\n    pub fn one() {}\n}\n\nfn after() {\n    let count: u8 = \"three\";\n    println!(\"{}\", count);\n}\n",
+        )?;
+
+        let manifest = Manifest::load(temp_dir.path())?;
+        let target = select_target(&manifest, None, false)?;
+        let standalone = StandaloneManifest::new(&manifest, &target, false, false);
+        let args = Args {
+            verify: true,
+            ..Args::default()
+        };
+        let merged = merge_target(&manifest, temp_dir.path(), &target, &args)?;
+        let diagnostics = verify::check(&standalone, &target, &merged.code, &merged.lines, None)?;
+        let mut origins: Vec<usize> = diagnostics
+            .iter()
+            .filter(|d| d.is_error())
+            .map(|d| d.origin.as_ref().unwrap().line)
+            .collect();
+        origins.sort();
+        // The module's redefinition of `helpers`, and the code after the module
+        assert_eq!(origins, [5, 10]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_map() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("shapes");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        fs::write(
+            src_dir.join("main.rs"),
+            "mod shapes;\n\nfn main() {\n    // Print the area\n    println!(\"{}\", shapes::area(2.0));\n}\n",
+        )?;
+        fs::write(
+            src_dir.join("shapes.rs"),
+            "// Shapes\n\npub fn area(side: f64) -> f64 {\n    side * side\n}\n",
+        )?;
+
+        let output_file = temp_dir.path().join("out.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            source_map: true,
+            ..Args::default()
+        };
+        process_single_package("shapes", temp_dir.path(), &args)?;
+        assert_eq!(
+            fs::read_to_string(&output_file)?,
+            "// main.rs\nmod shapes {\n    // shapes.rs\n    pub fn area(side: f64) -> f64 {\n        side * side\n    }\n}\nfn main() {\n    println!(\"{}\", shapes::area(2.0));\n}\n"
+        );
+        let source_map = fs::read_to_string(temp_dir.path().join("out.rs.map.json"))?;
+        let src_dir = src_dir.canonicalize()?;
+        assert_eq!(
+            source_map,
+            format!(
+                r#"{{
+  "file": "out.rs",
+  "sources": {{
+    "main.rs": "{}",
+    "shapes.rs": "{}"
+  }},
+  "mappings": [
+    {{ "lines": [1, 2], "source": "main.rs", "line": 1, "column": 1, "end_line": 1 }},
+    {{ "lines": [3, 3], "source": "shapes.rs", "line": 1, "column": 1, "end_line": 1 }},
+    {{ "lines": [4, 7], "source": "shapes.rs", "line": 3, "column": 1, "end_line": 5 }},
+    {{ "lines": [8, 8], "source": "main.rs", "line": 3, "column": 1, "end_line": 3 }},
+    {{ "lines": [9, 10], "source": "main.rs", "line": 5, "column": 5, "end_line": 6 }}
+  ]
+}}
+"#,
+                src_dir.join("main.rs").display(),
+                src_dir.join("shapes.rs").display()
+            )
+        );
+
+        // The comment-preserving merge copies lines one for one
+        let args = Args {
+            preserve_comments: true,
+            ..args
+        };
+        process_single_package("shapes", temp_dir.path(), &args)?;
+        let source_map = fs::read_to_string(temp_dir.path().join("out.rs.map.json"))?;
+        assert!(source_map.contains(
+            r#"{ "lines": [3, 8], "source": "shapes.rs", "line": 1, "column": 1, "end_line": 5 },
+    { "lines": [9, 14], "source": "main.rs", "line": 1, "column": 1, "end_line": 6 }"#
+        ));
+
+        // Lines of a file that has since shrunk come from nowhere
+        let manifest = Manifest::load(temp_dir.path())?;
+        let target = select_target(&manifest, None, false)?;
+        let args = Args {
+            preserve_comments: false,
+            ..args
+        };
+        let merged = merge_target(&manifest, temp_dir.path(), &target, &args)?;
+        fs::write(src_dir.join("shapes.rs"), "// Shapes\n")?;
+        let located = merged.lines.locate_all(&merged.code);
+        assert!(located
+            .iter()
+            .flatten()
+            .all(|location| location.path == "main.rs" || location.line == 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_map_keeps_files_with_the_same_name_apart() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("twins");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        // A file outside the src directory goes by its name
+        let shared_dir = temp_dir.path().canonicalize()?.join("shared");
+        fs::create_dir(&shared_dir)?;
+        fs::write(
+            src_dir.join("lib.rs"),
+            format!(
+                "#[path = {:?}]\npub mod shared;\n",
+                shared_dir.join("lib.rs")
+            ),
+        )?;
+        fs::write(shared_dir.join("lib.rs"), "pub fn shared() {}\n")?;
+
+        let output_file = temp_dir.path().join("twins.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            source_map: true,
+            ..Args::default()
+        };
+        process_single_package("twins", temp_dir.path(), &args)?;
+        let source_map = fs::read_to_string(temp_dir.path().join("twins.rs.map.json"))?;
+        let root = temp_dir.path().canonicalize()?;
+        assert!(source_map.contains(&format!(
+            "  \"sources\": {{\n    \"lib.rs\": \"{}\",\n    \"lib.rs#2\": \"{}\"\n  }},",
+            root.join("src/lib.rs").display(),
+            root.join("shared/lib.rs").display()
+        )));
+        assert!(source_map.contains(r#""source": "lib.rs#2", "line": 1"#));
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_map_with_inline_lib() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let package_dir = temp_dir.path().join("my-app");
+        fs::create_dir_all(package_dir.join("src"))?;
+        fs::write(
+            package_dir.join("Cargo.toml"),
+            "[package]\nname = \"my-app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )?;
+        fs::write(
+            package_dir.join("src/lib.rs"),
+            "//! The app\n\npub struct Config {\n    pub verbose: bool,\n}\n",
+        )?;
+        fs::write(
+            package_dir.join("src/main.rs"),
+            "use my_app::Config;\n\nfn main() {\n    let config = Config { verbose: true };\n    assert!(config.verbose);\n}\n",
+        )?;
+
+        let output_file = temp_dir.path().join("my-app.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            inline_lib: true,
+            source_map: true,
+            ..Args::default()
+        };
+        process_single_package("my-app", &package_dir, &args)?;
+        assert_eq!(
+            fs::read_to_string(&output_file)?,
+            "// main.rs\n#[macro_use]\npub mod my_app {\n    #![doc = \" The app\"]\n    // lib.rs (#[path])\n    pub struct Config {\n        pub verbose: bool,\n    }\n}\nuse crate::my_app::Config;\nfn main() {\n    let config = Config { verbose: true };\n    assert!(config.verbose);\n}\n"
+        );
+        // The wrapper the merge puts around the library maps nowhere, and the
+        // binary's code after it maps back to main.rs
+        let source_map = fs::read_to_string(temp_dir.path().join("my-app.rs.map.json"))?;
+        assert!(source_map.contains(
+            r#"  "mappings": [
+    { "lines": [1, 1], "source": "main.rs", "line": 1, "column": 1, "end_line": 1 },
+    { "lines": [5, 5], "source": "lib.rs", "line": 1, "column": 1, "end_line": 1 },
+    { "lines": [6, 9], "source": "lib.rs", "line": 3, "column": 1, "end_line": 5 },
+    { "lines": [10, 10], "source": "main.rs", "line": 1, "column": 1, "end_line": 1 },
+    { "lines": [11, 14], "source": "main.rs", "line": 3, "column": 1, "end_line": 6 }
+  ]"#
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_annotate_items() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("items");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        fs::write(
+            src_dir.join("main.rs"),
+            "mod util;\n\nuse util::used;\n\nfn main() {\n    used();\n}\n",
+        )?;
+        fs::write(
+            src_dir.join("util.rs"),
+            "pub fn used() {\n    println!(\"used\");\n}\n\npub fn unused() {}\n\npub struct Point {\n    pub x: i32,\n}\n",
+        )?;
+
+        let output_file = temp_dir.path().join("items.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            annotate_items: true,
+            ..Args::default()
+        };
+        process_single_package("items", temp_dir.path(), &args)?;
+        assert_eq!(
+            fs::read_to_string(&output_file)?,
+            "// main.rs\n// main.rs:1\nmod util {\n    // util.rs\n    // util.rs:1-3\n    pub fn used() {\n        println!(\"used\");\n    }\n    // util.rs:5\n    pub fn unused() {}\n    // util.rs:7-9\n    pub struct Point {\n        pub x: i32,\n    }\n}\nuse util::used;\n// main.rs:5-7\nfn main() {\n    used();\n}\n"
+        );
+
+        // Items dropped by tree shaking take their comments along
+        let args = Args {
+            tree_shake: true,
+            ..args
+        };
+        process_single_package("items", temp_dir.path(), &args)?;
+        assert_eq!(
+            fs::read_to_string(&output_file)?,
+            "// main.rs\n// main.rs:1\nmod util {\n    // util.rs\n    // util.rs:1-3\n    pub fn used() {\n        println!(\"used\");\n    }\n}\nuse util::used;\n// main.rs:5-7\nfn main() {\n    used();\n}\n"
+        );
+
+        let args = Args {
+            tree_shake: false,
+            preserve_comments: true,
+            ..args
+        };
+        process_single_package("items", temp_dir.path(), &args)?;
+        assert_eq!(
+            fs::read_to_string(&output_file)?,
+            "// main.rs\n// main.rs:1\nmod util {\n// util.rs\n// util.rs:1-3\npub fn used() {\n    println!(\"used\");\n}\n\n// util.rs:5\npub fn unused() {}\n\n// util.rs:7-9\npub struct Point {\n    pub x: i32,\n}\n}\n\nuse util::used;\n\n// main.rs:5-7\nfn main() {\n    used();\n}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_annotate_module_items() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("mods");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(src_dir.join("net"))?;
+        fs::write(
+            src_dir.join("main.rs"),
+            "fn main() {\n    net::tcp::connect();\n}\n\n/// Networking\npub mod net;\n\nmod inner {\n    pub fn one() -> u8 {\n        1\n    }\n}\n",
+        )?;
+        fs::write(src_dir.join("net.rs"), "// Transports\npub mod tcp;\n")?;
+        fs::write(src_dir.join("net/tcp.rs"), "pub fn connect() {}\n")?;
+
+        let output_file = temp_dir.path().join("mods.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            annotate_items: true,
+            ..Args::default()
+        };
+        process_single_package("mods", temp_dir.path(), &args)?;
+        assert_eq!(
+            fs::read_to_string(&output_file)?,
+            "// main.rs\n// main.rs:1-3\nfn main() {\n    net::tcp::connect();\n}\n// main.rs:5-6\n#[doc = \" Networking\"]\npub mod net {\n    // net.rs\n    // net.rs:2\n    pub mod tcp {\n        // net/tcp.rs\n        // net/tcp.rs:1\n        pub fn connect() {}\n    }\n}\n// main.rs:8-12\nmod inner {\n    // main.rs:9-11\n    pub fn one() -> u8 {\n        1\n    }\n}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unmerge() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("split");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(src_dir.join("shapes"))?;
+        fs::create_dir_all(src_dir.join("other"))?;
+        let sources = [
+            ("main.rs", "mod shapes;\n#[path = \"other/extra.rs\"]\nmod extra;\n\nmod inline {\n    pub fn one() -> u8 {\n        1\n    }\n}\n\nfn main() {\n    // Print them\n    println!(\"{} {}\", shapes::area(), extra::EXTRA + inline::one());\n}\n"),
+            ("shapes.rs", "mod circle;\n\n/// Area of the unit circle\npub fn area() -> f64 {\n    circle::area(1.0)\n}\n"),
+            ("shapes/circle.rs", "pub fn area(r: f64) -> f64 {\n    3.14 * r * r\n}\n"),
+            ("other/extra.rs", "pub const EXTRA: u8 = 2;\n"),
+        ];
+        for (path, text) in sources {
+            fs::write(src_dir.join(path), text)?;
+        }
+
+        // Comments and all, the files come back as they were
+        let output_file = temp_dir.path().join("split.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            preserve_comments: true,
+            ..Args::default()
+        };
+        process_single_package("split", temp_dir.path(), &args)?;
+        let files = unmerge::unmerge(&fs::read_to_string(&output_file)?)?;
+        let paths: Vec<&Path> = files.iter().map(|file| file.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            ["main.rs", "shapes.rs", "shapes/circle.rs", "other/extra.rs"].map(Path::new)
+        );
+        for file in &files {
+            assert_eq!(file.text, fs::read_to_string(src_dir.join(&file.path))?);
+        }
+
+        // After a token merge they're in their formatted form, item comments
+        // aside, and merge back the same
+        let args = Args {
+            preserve_comments: false,
+            ..args
+        };
+        process_single_package("split", temp_dir.path(), &args)?;
+        let merged = fs::read_to_string(&output_file)?;
+        let annotated = Args {
+            annotate_items: true,
+            ..args.clone()
+        };
+        process_single_package("split", temp_dir.path(), &annotated)?;
+        let files = unmerge::unmerge(&fs::read_to_string(&output_file)?)?;
+        assert_eq!(
+            files[0].text,
+            "mod shapes;\n#[path = \"other/extra.rs\"]\nmod extra;\nmod inline {\n    pub fn one() -> u8 {\n        1\n    }\n}\nfn main() {\n    println!(\"{} {}\", shapes::area(), extra::EXTRA + inline::one());\n}\n"
+        );
+        assert_eq!(
+            files[1].text,
+            "mod circle;\n#[doc = \" Area of the unit circle\"]\npub fn area() -> f64 {\n    circle::area(1.0)\n}\n"
+        );
+        for file in &files {
+            fs::write(src_dir.join(&file.path), &file.text)?;
+        }
+        process_single_package("split", temp_dir.path(), &args)?;
+        assert_eq!(fs::read_to_string(&output_file)?, merged);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unmerge_keeps_comments_that_look_like_file_comments() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("notes");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(&src_dir)?;
+        let main = "mod helpers {\n    // moved here from legacy.rs\n    pub fn one() -> u8 {\n        1\n    }\n}\n\nmod shim {\n    // legacy.rs\n    pub fn two() -> u8 {\n        2\n    }\n}\n\nfn main() {\n    println!(\"{}\", helpers::one() + shim::two());\n}\n";
+        fs::write(src_dir.join("main.rs"), main)?;
+
+        let output_file = temp_dir.path().join("notes.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            preserve_comments: true,
+            ..Args::default()
+        };
+        process_single_package("notes", temp_dir.path(), &args)?;
+
+        // Neither comment names a file the module could come from
+        let files = unmerge::unmerge(&fs::read_to_string(&output_file)?)?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, Path::new("main.rs"));
+        assert_eq!(files[0].text, main);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_edits() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("edited");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        fs::write(
+            src_dir.join("main.rs"),
+            "mod shapes;\n\nfn main() {\n    // Print the area\n    println!(\"{}\", shapes::area(2.0));\n}\n",
+        )?;
+        fs::write(
+            src_dir.join("shapes.rs"),
+            "/// Area of a circle\npub fn area(r: f64) -> f64 {\n    3.14 * r * r\n}\n",
+        )?;
+
+        let edited_file = temp_dir.path().join("edited.rs");
+        let args = Args {
+            output_path: Some(edited_file.clone()),
+            ..Args::default()
+        };
+        process_single_package("edited", temp_dir.path(), &args)?;
+        let merged = fs::read_to_string(&edited_file)?;
+        let manifest = Manifest::load(temp_dir.path())?;
+        let target = select_target(&manifest, None, false)?;
+        let fresh = merge_target(&manifest, temp_dir.path(), &target, &args)?;
+        let file_of = |path: &str| src_dir.join(path);
+
+        // Changed and added lines go to their files, in the files' own layout,
+        // and a new module block becomes a new file
+        let edited = merged
+            .replace("3.14 * r * r", "std::f64::consts::PI * r * r")
+            .replace(
+                "fn main() {",
+                "mod units {\n    // units.rs\n    pub const UNIT: &str = \"cm\";\n}\nfn main() {",
+            )
+            .replace(
+                "shapes::area(2.0));\n",
+                "shapes::area(2.0));\n    println!(\"{}\", units::UNIT);\n",
+            );
+        let patches = patch::file_patches(&fresh.code, &edited, file_of)?;
+        let diff: String = patches
+            .iter()
+            .map(|file_patch| {
+                let label = file_patch.file.strip_prefix(temp_dir.path()).unwrap();
+                patch::unified_diff(
+                    &label.to_string_lossy(),
+                    file_patch.original.as_deref(),
+                    file_patch.patched.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            diff,
+            "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1,6 +1,8 @@\n mod shapes;\n+mod units;\n \n fn main() {\n     // Print the area\n     println!(\"{}\", shapes::area(2.0));\n+    println!(\"{}\", units::UNIT);\n }\n--- a/src/shapes.rs\n+++ b/src/shapes.rs\n@@ -1,4 +1,4 @@\n /// Area of a circle\n pub fn area(r: f64) -> f64 {\n-    3.14 * r * r\n+    std::f64::consts::PI * r * r\n }\n--- /dev/null\n+++ b/src/units.rs\n@@ -0,0 +1 @@\n+pub const UNIT: &str = \"cm\";\n"
+        );
+
+        // Doc comments come out of a merge as attributes, so they can't be matched
+        let edited = merged.replace("Area of a circle", "Area of a disc");
+        let error = patch::file_patches(&fresh.code, &edited, file_of).unwrap_err();
+        assert!(format!("{:#}", error).contains("shapes.rs: `#[doc = \" Area of a circle\"]`"));
+
+        // Written in place, the edits leave the files merging into the edited file
+        fs::write(&edited_file, merged.replace("3.14", "3.0"))?;
+        let args = Args {
+            output_path: None,
+            apply: Some(edited_file.clone()),
+            write: true,
+            ..args
+        };
+        apply_edits(temp_dir.path(), &edited_file, &args)?;
+        assert_eq!(
+            fs::read_to_string(src_dir.join("shapes.rs"))?,
+            "/// Area of a circle\npub fn area(r: f64) -> f64 {\n    3.0 * r * r\n}\n"
+        );
+        let fresh = merge_target(&manifest, temp_dir.path(), &target, &args)?;
+        assert_eq!(fresh.code, fs::read_to_string(&edited_file)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_output() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("committed");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        fs::write(src_dir.join("main.rs"), "mod util;\nfn main() {}\n")?;
+        fs::write(src_dir.join("util.rs"), "pub fn one() -> u8 {\n    1\n}\n")?;
+
+        let output_file = temp_dir.path().join("bundle").join("committed.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            check: true,
+            ..Args::default()
+        };
+        // Nothing is written, even when there's nothing there yet
+        assert!(process_single_package("committed", temp_dir.path(), &args).is_err());
+        assert!(!output_file.exists());
+
+        let write = Args {
+            check: false,
+            ..args.clone()
+        };
+        process_single_package("committed", temp_dir.path(), &write)?;
+        process_single_package("committed", temp_dir.path(), &args)?;
+
+        fs::write(src_dir.join("util.rs"), "pub fn one() -> u8 {\n    2\n}\n")?;
+        let bundle = fs::read_to_string(&output_file)?;
+        let error = process_single_package("committed", temp_dir.path(), &args).unwrap_err();
+        assert!(error.to_string().contains("out of date (files: 1)"));
+        assert_eq!(fs::read_to_string(&output_file)?, bundle);
+        assert_eq!(
+            patch::diff_summary(&bundle, &bundle.replace("1\n", "2\n")),
+            (1, 1, 5)
+        );
+        // Large outputs are summed up without a table of every pair of lines
+        let large: String = (0..100_000)
+            .map(|i| format!("const C{}: u32 = {};\n", i, i))
+            .collect();
+        let edited = large
+            .replace("const C7: u32 = 7;\n", "")
+            .replace("const C90000: u32", "static C90000: u32");
+        assert_eq!(patch::diff_summary(&large, &edited), (1, 2, 8));
+        // and diffed for `apply` in linear space
+        let diff = patch::unified_diff("large.rs", Some(&large), Some(&edited));
+        assert!(diff.contains("\n-const C7: u32 = 7;\n"));
+        assert!(diff.contains("\n-const C90000: u32 = 90000;\n+static C90000: u32 = 90000;\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stdout_takes_a_single_target() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("piped");
         let src_dir = temp_dir.path().join("src");
-        std::fs::create_dir_all(&src_dir).unwrap();
-
-        // Create a dummy Cargo.toml file inside the temp directory
-        let cargo_toml_path = temp_dir.path().join("Cargo.toml");
-        let mut cargo_toml = File::create(&cargo_toml_path).unwrap();
-        writeln!(
-            cargo_toml,
-            r#"[package]
-    name = "test_package"
-    version = "0.1.0"
-    edition = "2021"
-    "#,
-        )
-        .unwrap();
-
-        // Test the find_src_dir function
-        let src_dir_found = find_src_dir(temp_dir.path()).expect("Failed to find src dir");
-        assert_eq!(src_dir_found, src_dir);
+        fs::create_dir(&src_dir)?;
+        fs::write(src_dir.join("main.rs"), "fn main() {}\n")?;
+
+        let stdout = Args {
+            stdout: true,
+            ..Args::default()
+        };
+        for args in [
+            Args {
+                target: Some(TargetSelection::Bins),
+                ..stdout.clone()
+            },
+            Args {
+                source_map: true,
+                ..stdout.clone()
+            },
+            Args {
+                check: true,
+                ..stdout.clone()
+            },
+            Args {
+                emit_crate: Some(temp_dir.path().join("crate")),
+                ..stdout.clone()
+            },
+        ] {
+            let error = process_single_package("piped", temp_dir.path(), &args).unwrap_err();
+            assert!(error
+                .to_string()
+                .starts_with("--output - writes a single target"));
+        }
+        assert!(process_all_packages(temp_dir.path(), &stdout).is_err());
+        assert!(!temp_dir.path().join("crate").exists());
+        Ok(())
     }
 
     #[test]
-    fn test_parse_module_structure() {
-        let temp_dir = TempDir::new().unwrap();
-        std::fs::create_dir_all(temp_dir.path().join("src")).unwrap();
-
-        let lib_rs_path = temp_dir.path().join("src/lib.rs");
-        let mut lib_rs = File::create(&lib_rs_path).unwrap();
-        writeln!(lib_rs, "pub mod example_module;").unwrap();
+    fn test_markdown_format() -> Result<()> {
+        let (temp_dir, cargo_toml) = setup_temp_cargo_toml("documented");
+        fs::write(
+            &cargo_toml,
+            "[package]\nname = \"documented\"\nversion = \"0.3.1\"\nedition = \"2021\"\n",
+        )?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(src_dir.join("shapes"))?;
+        fs::write(
+            src_dir.join("main.rs"),
+            "mod shapes;\nmod units;\n\nfn main() {}\n",
+        )?;
+        fs::write(src_dir.join("shapes.rs"), "pub mod circle;\n")?;
+        fs::write(src_dir.join("shapes/circle.rs"), "pub fn area() {}\n")?;
+        // A line of the code that would close the fence makes for a longer one
+        fs::write(
+            src_dir.join("units.rs"),
+            "/// ```\n/// assert_eq!(units::CM, 1);\n/// ```\npub const CM: u8 = 1;\npub const FENCE: &str = \"\n```\n\";\n",
+        )?;
 
-        let mod_path = temp_dir.path().join("src/example_module.rs");
-        let mut mod_file = File::create(&mod_path).unwrap();
-        writeln!(mod_file, "pub fn example_function() -> i32 {{ 42 }}").unwrap();
+        let output_file = temp_dir.path().join("documented.md");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            preserve_comments: true,
+            format: OutputFormat::Markdown,
+            ..Args::default()
+        };
+        process_single_package("documented", temp_dir.path(), &args)?;
+        assert_eq!(
+            fs::read_to_string(&output_file)?,
+            "# documented 0.3.1\n\nThe bin target `documented`, merged from 4 files.\n\n## Modules\n\n```text\ncrate (src/main.rs)\n├── shapes (src/shapes.rs)\n│   └── circle (src/shapes/circle.rs)\n└── units (src/units.rs)\n```\n\n## Files\n\n### src/main.rs\n\n```rust\nmod shapes;\nmod units;\n\nfn main() {}\n```\n\n### src/shapes.rs\n\n```rust\npub mod circle;\n```\n\n### src/shapes/circle.rs\n\n```rust\npub fn area() {}\n```\n\n### src/units.rs\n\n````rust\n/// ```\n/// assert_eq!(units::CM, 1);\n/// ```\npub const CM: u8 = 1;\npub const FENCE: &str = \"\n```\n\";\n````\n"
+        );
+
+        // The closing fence goes on a line of its own, after code that ends without one
+        fs::write(
+            src_dir.join("main.rs"),
+            "mod shapes;\nmod units;\n\nfn main() {}",
+        )?;
+        process_single_package("documented", temp_dir.path(), &args)?;
+        let markdown = fs::read_to_string(&output_file)?;
+        assert!(markdown.contains("\nfn main() {}\n```\n"));
+        Ok(())
+    }
 
-        let module_structure = parse_module_structure(&temp_dir.path().join("src"))
-            .expect("Failed to parse module structure");
+    #[test]
+    fn test_size_limits() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("limited");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        fs::write(
+            src_dir.join("main.rs"),
+            "mod table;\n/// Prints the table\nfn main() { println!(\"{}\", table::TABLE.len()); }\n",
+        )?;
+        fs::write(
+            src_dir.join("table.rs"),
+            "pub const TABLE: [u32; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];\npub const SQUARES: [u32; 8] = [1, 4, 9, 16, 25, 36, 49, 64];\n",
+        )?;
 
-        assert!(module_structure.contains_key("crate"));
-        assert!(module_structure.contains_key("example_module"));
+        let manifest = Manifest::load(temp_dir.path())?;
+        let target = select_target(&manifest, None, false)?;
+        let merged = merge_target(&manifest, temp_dir.path(), &target, &Args::default())?;
+        let paths: Vec<&str> = merged.files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["table.rs", "main.rs"]);
+        let tokens: usize = merged.files.iter().map(|file| file.tokens).sum();
+        assert_eq!(tokens, size::count_tokens(&merged.code)?);
+        // `mod table { }` and `fn main ( ) { println ! ( "{}" , table :: TABLE . len ( ) ) ; }`
+        assert_eq!(merged.files[1].tokens, 25);
+
+        let output_file = temp_dir.path().join("limited.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            max_bytes: Some(merged.code.len()),
+            max_tokens: Some(tokens),
+            ..Args::default()
+        };
+        process_single_package("limited", temp_dir.path(), &args)?;
+
+        let args = Args {
+            max_tokens: Some(tokens - 1),
+            ..args
+        };
+        let err = process_single_package("limited", temp_dir.path(), &args).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Merged output is over the size limit: {} tokens (--max-tokens {})",
+                tokens,
+                tokens - 1
+            )
+        );
+        // The output is still written, to look into
+        assert!(output_file.exists());
+        Ok(())
     }
 
     #[test]
-    fn test_format_rust_code() {
-        let code = r#"fn main() {println!("Hello, world!");}"#;
-        let formatted_code = format_rust_code(code).expect("Failed to format code");
-        assert!(formatted_code.contains("fn main() {"));
+    fn test_minify() -> Result<()> {
+        let (temp_dir, _) = setup_temp_cargo_toml("tiny");
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        fs::write(
+            src_dir.join("main.rs"),
+            "mod geometry;\nuse geometry::Point;\n/// Entry point\nfn main() {\n    let horizontal = 3;\n    let vertical = -4;\n    let point = Point { horizontal, vertical };\n    let Point { horizontal, .. } = point;\n    let label = format!(\"{horizontal}\");\n    assert_eq!(label, \"3\");\n    assert_eq!(point.manhattan(), 7);\n    assert_eq!((1..3).len(), 2);\n}\n",
+        )?;
+        fs::write(
+            src_dir.join("geometry.rs"),
+            "//! Points on a grid\n#[derive(Clone, Copy)]\npub struct Point { pub horizontal: i32, pub vertical: i32 }\nimpl Point {\n    pub fn manhattan(&self) -> i32 {\n        let total = self.horizontal.abs() + self.vertical.abs();\n        let width = 4;\n        let precision = 1;\n        assert_eq!(format!(\"{:>width$}|{:.precision$}\", total, 2.0), \"   7|2.0\");\n        total\n    }\n}\n",
+        )?;
+
+        let output_file = temp_dir.path().join("tiny.rs");
+        let args = Args {
+            output_path: Some(output_file.clone()),
+            minify: true,
+            shorten_idents: true,
+            ..Args::default()
+        };
+        process_single_package("tiny", temp_dir.path(), &args)?;
+
+        let merged = fs::read_to_string(&output_file)?;
+        assert_eq!(merged.lines().count(), 1);
+        assert!(!merged.contains("doc"));
+        assert!(!merged.contains("geometry.rs"));
+        assert!(merged.contains("pub struct Point{pub horizontal:i32,pub vertical:i32}"));
+        assert!(merged.contains("let a=Point{horizontal,vertical:c};"));
+        // Captured by the format string, so it keeps its name
+        assert!(merged.contains("let horizontal=3;"));
+        // Named width and precision are captured too
+        assert!(merged.contains("let width=4;"));
+        assert!(merged.contains("let precision=1;"));
+        assert!(!merged.contains("total"));
+
+        assert_compiles(&output_file)?;
+        let status = Command::new(output_file.with_extension("bin")).status()?;
+        assert!(status.success());
+        Ok(())
     }
 
     #[test]
@@ -173,11 +1509,13 @@ edition = "2021"
         assert!(module_structure.contains_key("module_b::mod_b2"));
         assert!(module_structure.contains_key("module_b::submodule::mod_b3"));
 
-        let processed = process_package(&temp_dir.path().join("src"), &module_structure)
-            .expect("Failed to process package");
-
-        let formated_code =
-            format_rust_code(&processed.to_string()).expect("Failed to format code");
+        let formated_code = merge_package(
+            &temp_dir.path().join("src"),
+            &module_structure,
+            &[],
+            &FormatOptions::default(),
+        )
+        .expect("Failed to merge package");
         println!("{}", formated_code);
     }
 
@@ -197,11 +1535,13 @@ edition = "2021"
         let module_structure = parse_module_structure(&temp_dir.path().join("src"))
             .expect("Failed to parse module structure");
 
-        let processed = process_package(&temp_dir.path().join("src"), &module_structure)
-            .expect("Failed to process package");
-
-        let formated_code =
-            format_rust_code(&processed.to_string()).expect("Failed to format code");
+        let formated_code = merge_package(
+            &temp_dir.path().join("src"),
+            &module_structure,
+            &[],
+            &FormatOptions::default(),
+        )
+        .expect("Failed to merge package");
         println!("{}", formated_code);
     }
 
@@ -237,11 +1577,13 @@ edition = "2021"
         // Assert that all modules are present in the parsed structure
         assert!(module_structure.contains_key("crate"));
 
-        let processed = process_package(&temp_dir.path().join("src"), &module_structure)
-            .expect("Failed to process package");
-
-        let formated_code =
-            format_rust_code(&processed.to_string()).expect("Failed to format code");
+        let formated_code = merge_package(
+            &temp_dir.path().join("src"),
+            &module_structure,
+            &[],
+            &FormatOptions::default(),
+        )
+        .expect("Failed to merge package");
         println!("{}", formated_code);
     }
 
@@ -316,11 +1658,13 @@ edition = "2021"
         assert!(module_structure.contains_key("module_b::mod_b2"));
         assert!(module_structure.contains_key("module_b::submodule::mod_b3"));
 
-        let processed = process_package(&temp_dir.path().join("src"), &module_structure)
-            .expect("Failed to process package");
-
-        let formated_code =
-            format_rust_code(&processed.to_string()).expect("Failed to format code");
+        let formated_code = merge_package(
+            &temp_dir.path().join("src"),
+            &module_structure,
+            &[],
+            &FormatOptions::default(),
+        )
+        .expect("Failed to merge package");
 
         println!("{}", formated_code);
     }
@@ -384,11 +1728,11 @@ pub fn nested_function() -> i32 {
             "crate",
             &mut module_structure,
             Path::new("src/main.rs"),
+            &ModuleDir::crate_root(&src_dir.join("main.rs")),
         )?;
 
-        let processed = process_package(&src_dir, &module_structure)?;
-
-        let formatted_code = format_rust_code(&processed.to_string())?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
 
         println!("{}", formatted_code);
 
@@ -417,6 +1761,7 @@ pub fn nested_function() -> i32 {
             "mod",
             &mut module_structure,
             Path::new("test.rs"),
+            &ModuleDir::crate_root(file_path),
         )
         .unwrap();
 
@@ -450,6 +1795,7 @@ pub fn nested_function() -> i32 {
             "crate",
             &mut module_structure,
             Path::new("src"),
+            &ModuleDir::crate_root(Path::new("src/lib.rs")),
         )
         .unwrap();
 
@@ -457,6 +1803,442 @@ pub fn nested_function() -> i32 {
         assert!(module_structure.contains_key("crate::normal_mod"));
     }
 
+    #[test]
+    fn test_path_attribute_on_external_module() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(src_dir.join("platform"))?;
+
+        fs::write(
+            src_dir.join("lib.rs"),
+            r#"
+            #[path = "platform/linux.rs"]
+            mod sys;
+            "#,
+        )?;
+        fs::write(
+            src_dir.join("platform/linux.rs"),
+            "pub fn linux_fn() {}\nmod helper;",
+        )?;
+        // Files loaded through `#[path]` resolve their children next to themselves
+        fs::write(src_dir.join("platform/helper.rs"), "pub fn helper_fn() {}")?;
+
+        let module_structure = parse_module_structure(&src_dir)?;
+        assert!(module_structure.contains_key("sys"));
+        assert!(module_structure.contains_key("sys::helper"));
+
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
+
+        assert!(formatted_code.contains("mod sys {"));
+        assert!(formatted_code.contains("linux_fn"));
+        assert!(formatted_code.contains("helper_fn"));
+        assert!(formatted_code.contains("// platform/linux.rs"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_attribute_inside_inline_module() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(src_dir.join("outer/inner"))?;
+        fs::create_dir_all(src_dir.join("custom"))?;
+
+        // In a mod-rs file, inline blocks add their names as directories
+        fs::write(
+            src_dir.join("lib.rs"),
+            r#"
+            mod outer {
+                #[path = "inner_impl.rs"]
+                mod inner;
+            }
+            #[path = "custom"]
+            mod renamed {
+                #[path = "leaf.rs"]
+                mod leaf;
+            }
+            mod nested;
+            "#,
+        )?;
+        fs::write(src_dir.join("outer/inner_impl.rs"), "pub fn inner_fn() {}")?;
+        fs::write(src_dir.join("custom/leaf.rs"), "pub fn leaf_fn() {}")?;
+
+        // In a non-mod-rs file, they start from a directory named after the file
+        fs::write(
+            src_dir.join("nested.rs"),
+            r#"
+            mod block {
+                #[path = "deep.rs"]
+                mod deep;
+            }
+            "#,
+        )?;
+        fs::create_dir_all(src_dir.join("nested/block"))?;
+        fs::write(src_dir.join("nested/block/deep.rs"), "pub fn deep_fn() {}")?;
+
+        let module_structure = parse_module_structure(&src_dir)?;
+        assert!(module_structure.contains_key("outer::inner"));
+        assert!(module_structure.contains_key("renamed::leaf"));
+        assert!(module_structure.contains_key("nested::block::deep"));
+
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
+
+        assert!(formatted_code.contains("inner_fn"));
+        assert!(formatted_code.contains("leaf_fn"));
+        assert!(formatted_code.contains("deep_fn"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_attribute_per_platform() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(&src_dir)?;
+
+        fs::write(
+            src_dir.join("lib.rs"),
+            r#"
+            #[cfg_attr(unix, path = "unix.rs")]
+            #[cfg_attr(not(unix), path = "other.rs")]
+            mod imp;
+
+            #[cfg(unix)]
+            #[path = "unix.rs"]
+            mod backend;
+            #[cfg(not(unix))]
+            #[path = "other.rs"]
+            mod backend;
+            "#,
+        )?;
+        fs::write(src_dir.join("unix.rs"), "pub fn unix_fn() {}")?;
+        fs::write(src_dir.join("other.rs"), "pub fn other_fn() {}")?;
+
+        let module_structure = parse_module_structure(&src_dir)?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
+
+        let file = syn::parse_file(&formatted_code)?;
+        let modules: Vec<String> = file
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Mod(item_mod) => Some(item_mod.to_token_stream().to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(modules.len(), 3);
+        let expected_imp = if cfg!(unix) { "unix_fn" } else { "other_fn" };
+        assert!(modules[0].contains(expected_imp));
+        assert!(modules[1].contains("cfg (unix)") && modules[1].contains("unix_fn"));
+        assert!(modules[2].contains("cfg (not (unix))") && modules[2].contains("other_fn"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_deeply_nested_non_mod_rs_modules() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(src_dir.join("a/b"))?;
+        fs::create_dir_all(src_dir.join("x/y"))?;
+
+        fs::write(
+            src_dir.join("lib.rs"),
+            "mod a;\nmod x { mod y; }\nmod r#type;",
+        )?;
+        fs::write(src_dir.join("a.rs"), "mod b;")?;
+        fs::write(src_dir.join("a/b.rs"), "mod c;\nmod inline { mod d; }")?;
+        fs::write(src_dir.join("a/b/c.rs"), "pub fn c_fn() {}")?;
+        fs::create_dir_all(src_dir.join("a/b/inline"))?;
+        fs::write(src_dir.join("a/b/inline/d.rs"), "pub fn d_fn() {}")?;
+        fs::write(src_dir.join("x/y/mod.rs"), "pub fn y_fn() {}")?;
+        fs::write(src_dir.join("type.rs"), "pub fn type_fn() {}")?;
+
+        let module_structure = parse_module_structure(&src_dir)?;
+        assert!(module_structure.contains_key("a::b::c"));
+        assert!(module_structure.contains_key("a::b::inline::d"));
+        assert!(module_structure.contains_key("x::y"));
+        assert!(module_structure.contains_key("r#type"));
+
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
+
+        for expected in [
+            "c_fn",
+            "d_fn",
+            "y_fn",
+            "type_fn",
+            "// a/b/c.rs",
+            "// x/y/mod.rs",
+        ] {
+            assert!(formatted_code.contains(expected), "missing {}", expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_reports_ambiguous_module_files() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(src_dir.join("foo"))?;
+
+        fs::write(src_dir.join("lib.rs"), "mod foo;")?;
+        fs::write(src_dir.join("foo.rs"), "")?;
+        fs::write(src_dir.join("foo/mod.rs"), "")?;
+
+        let error = parse_module_structure(&src_dir).unwrap_err().to_string();
+        assert!(error.contains("E0761"), "{}", error);
+
+        fs::remove_file(src_dir.join("foo.rs"))?;
+        fs::remove_file(src_dir.join("foo/mod.rs"))?;
+        let error = parse_module_structure(&src_dir).unwrap_err().to_string();
+        assert!(error.contains("E0583"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_module_visibility() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(&src_dir)?;
+
+        fs::write(
+            src_dir.join("lib.rs"),
+            r#"
+            mod private_file;
+            pub(crate) mod crate_file;
+            pub mod public_inline {
+                pub(super) mod restricted_inline {}
+            }
+            "#,
+        )?;
+        fs::write(src_dir.join("private_file.rs"), "pub fn private_fn() {}")?;
+        fs::write(src_dir.join("crate_file.rs"), "pub fn crate_fn() {}")?;
+
+        let module_structure = parse_module_structure(&src_dir)?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
+
+        assert!(formatted_code.contains("\nmod private_file {"));
+        assert!(formatted_code.contains("pub(crate) mod crate_file {"));
+        assert!(formatted_code.contains("pub mod public_inline {"));
+        assert!(formatted_code.contains("pub(super) mod restricted_inline {"));
+        assert!(!formatted_code.contains("pub mod private_file"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_module_and_inner_attributes() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(&src_dir)?;
+
+        fs::write(
+            src_dir.join("lib.rs"),
+            r#"
+            //! Crate docs
+            #![allow(clippy::all)]
+
+            /// Macro helpers
+            #[macro_use]
+            mod macros;
+
+            #[rustfmt::skip]
+            #[cfg_attr(unix, path = "impl_unix.rs", allow(unused))]
+            #[cfg_attr(not(unix), path = "impl_unix.rs")]
+            mod imp;
+
+            mod inline {
+                #![allow(dead_code)]
+                fn unused() {}
+            }
+            "#,
+        )?;
+        fs::write(
+            src_dir.join("macros.rs"),
+            "//! Macro docs\n#![allow(unused_macros)]\nmacro_rules! m { () => {} }",
+        )?;
+        fs::write(src_dir.join("impl_unix.rs"), "pub fn imp_fn() {}")?;
+
+        let module_structure = parse_module_structure(&src_dir)?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
+
+        let file = syn::parse_file(&formatted_code)?;
+        assert_eq!(
+            file.attrs.len(),
+            2,
+            "crate inner attributes stay at the top"
+        );
+
+        let module = |name: &str| {
+            file.items
+                .iter()
+                .find_map(|item| match item {
+                    Item::Mod(item_mod) if item_mod.ident == name => Some(item_mod.clone()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let attr_paths = |item_mod: &ItemMod, style: AttrStyle| {
+            item_mod
+                .attrs
+                .iter()
+                .filter(|attr| attr.style == style)
+                .map(|attr| attr.path().to_token_stream().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let macros = module("macros");
+        assert_eq!(attr_paths(&macros, AttrStyle::Outer), ["doc", "macro_use"]);
+        assert_eq!(
+            attr_paths(&macros, AttrStyle::Inner(Default::default())),
+            ["doc", "allow"]
+        );
+
+        let imp = module("imp");
+        let imp_attrs = imp.to_token_stream().to_string();
+        assert!(imp_attrs.contains("rustfmt :: skip"));
+        assert!(!imp_attrs.contains("path"));
+        // The file comment must not swallow code rustfmt was told to skip
+        assert_eq!(imp.content.as_ref().map(|(_, items)| items.len()), Some(1));
+        if cfg!(unix) {
+            assert!(imp_attrs.contains("cfg_attr (unix , allow (unused))"));
+        }
+
+        let inline = module("inline");
+        assert_eq!(
+            attr_paths(&inline, AttrStyle::Inner(Default::default())),
+            ["allow"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_source_text_preserves_comments() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(src_dir.join("platform"))?;
+
+        fs::write(
+            src_dir.join("lib.rs"),
+            r#"//! Crate docs
+
+// Regular comment about the backend
+#[cfg(unix)]
+#[path = "platform/unix.rs"]
+pub(crate) mod backend;
+
+mod inline {
+    /* block comment */
+    mod nested;
+}
+
+#[cfg(test)]
+mod tests {
+    fn dropped() {}
+}
+"#,
+        )?;
+        fs::write(
+            src_dir.join("platform/unix.rs"),
+            "#![allow(unused)]\n\n// Keep me\npub fn  odd_spacing( ) {}\n",
+        )?;
+        fs::create_dir_all(src_dir.join("inline"))?;
+        fs::write(
+            src_dir.join("inline/nested.rs"),
+            "const X: u8 = 1; // trailing\n",
+        )?;
+
+        let (merged, _) = splice::merge_source_text(&src_dir.join("lib.rs"), &src_dir, false)?;
+
+        let expected = r#"// lib.rs
+//! Crate docs
+
+// Regular comment about the backend
+#[cfg(unix)]
+pub(crate) mod backend {
+// platform/unix.rs (#[path])
+#![allow(unused)]
+
+// Keep me
+pub fn  odd_spacing( ) {}
+}
+
+mod inline {
+    /* block comment */
+    mod nested {
+// inline/nested.rs
+const X: u8 = 1; // trailing
+}
+}
+
+"#;
+        assert_eq!(merged, expected);
+        syn::parse_file(&merged)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_comments_support_any_utf8_path() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(src_dir.join("données"))?;
+
+        fs::write(
+            src_dir.join("lib.rs"),
+            r#"
+            #[path = "données/mod+1 (copy).rs"]
+            mod data;
+            "#,
+        )?;
+        fs::write(
+            src_dir.join("données/mod+1 (copy).rs"),
+            "pub fn data_fn() {}",
+        )?;
+
+        let module_structure = parse_module_structure(&src_dir)?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
+
+        assert!(formatted_code.contains("// données/mod+1 (copy).rs (#[path])\n"));
+        assert!(!formatted_code.contains("__rustmerge_annotation"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_comments_leave_user_code_alone() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let src_dir = temp_dir.path().join("src");
+        fs::create_dir_all(&src_dir)?;
+
+        // Code that looks like the markers used by this and earlier versions
+        fs::write(
+            src_dir.join("lib.rs"),
+            r#"
+            const _: &'static str = "RUSTMERGE_COMMENT_lib__DOT__rs";
+            macro_rules! __rustmerge_annotation {
+                ($n:literal) => {};
+            }
+            __rustmerge_annotation!(0);
+            mod other;
+            "#,
+        )?;
+        fs::write(src_dir.join("other.rs"), "pub fn other_fn() {}")?;
+
+        let module_structure = parse_module_structure(&src_dir)?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
+
+        assert!(
+            formatted_code.contains(r#"const _: &'static str = "RUSTMERGE_COMMENT_lib__DOT__rs";"#)
+        );
+        assert!(formatted_code.contains("__rustmerge_annotation!(0);"));
+        assert!(formatted_code.starts_with("// lib.rs\n"));
+        assert!(formatted_code.contains("// other.rs\n"));
+        Ok(())
+    }
+
     #[test]
     fn test_ignore_cfg_test_module() -> Result<()> {
         let temp_dir = TempDir::new()?;
@@ -483,8 +2265,8 @@ pub fn nested_function() -> i32 {
         assert!(module_structure.contains_key("keep_this_module"));
         assert!(!module_structure.contains_key("ignore_this_module")); // Key check
 
-        let processed_code = process_package(&src_dir, &module_structure)?.to_string();
-        let formatted_code = format_rust_code(&processed_code)?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
 
         assert!(formatted_code.contains("keep_this_module"));
         assert!(formatted_code.contains("keep_fn"));
@@ -518,8 +2300,8 @@ pub fn nested_function() -> i32 {
         )?;
 
         let module_structure = parse_module_structure(&src_dir)?;
-        let processed_code = process_package(&src_dir, &module_structure)?.to_string();
-        let formatted_code = format_rust_code(&processed_code)?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
 
         assert!(formatted_code.contains("keep_this_fn"));
         assert!(!formatted_code.contains("ignore_this_fn"));
@@ -554,8 +2336,8 @@ pub fn nested_function() -> i32 {
         assert!(module_structure.contains_key("crate"));
         assert!(module_structure.contains_key("outer_module"));
 
-        let processed_code = process_package(&src_dir, &module_structure)?.to_string();
-        let formatted_code = format_rust_code(&processed_code)?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
 
         assert!(formatted_code.contains("outer_module"));
         assert!(formatted_code.contains("KeepStruct"));
@@ -584,8 +2366,8 @@ pub fn nested_function() -> i32 {
         )?;
 
         let module_structure = parse_module_structure(&src_dir)?;
-        let processed_code = process_package(&src_dir, &module_structure)?.to_string();
-        let formatted_code = format_rust_code(&processed_code)?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
 
         assert!(formatted_code.contains("MyStruct"));
         assert!(formatted_code.contains("MyEnum"));
@@ -619,8 +2401,8 @@ pub fn nested_function() -> i32 {
         assert!(module_structure.contains_key("keep_this_too"));
         assert!(!module_structure.contains_key("test"));
 
-        let processed_code = process_package(&src_dir, &module_structure)?.to_string();
-        let formatted_code = format_rust_code(&processed_code)?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
 
         assert!(formatted_code.contains("keep_this_too"));
         assert!(formatted_code.contains("another_kept_fn"));
@@ -653,8 +2435,8 @@ pub fn nested_function() -> i32 {
         assert!(module_structure.contains_key("keeper_module"));
         assert!(!module_structure.contains_key("tests"));
 
-        let processed_code = process_package(&src_dir, &module_structure)?.to_string();
-        let formatted_code = format_rust_code(&processed_code)?;
+        let formatted_code =
+            merge_package(&src_dir, &module_structure, &[], &FormatOptions::default())?;
 
         assert!(formatted_code.contains("keeper_module"));
         assert!(formatted_code.contains("some_public_fn"));
@@ -0,0 +1,504 @@
+// Dead code elimination on the merged crate.
+//
+// Reachability is worked out syntactically and by name only: starting from `main`
+// (or from the public items of a library), every identifier in a kept item marks
+// the items of that name as used, until nothing changes. Names are not resolved,
+// so two items called `new` in different places are kept or dropped together;
+// that errs on the side of keeping too much, never too little.
+
+use std::collections::HashSet;
+
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::{ImplItem, Item, ItemImpl, Type, UseTree, Visibility};
+
+use crate::annotate::Annotations;
+
+#[derive(Debug)]
+enum Node {
+    Always,                          // Kept no matter what: macro calls, `extern` blocks, ...
+    Named(String),                   // Kept when its name is used
+    Trait(String, Vec<String>),      // Kept when its name or the name of a member is used
+    TraitImpl(String, Vec<String>),  // Trait name and names in the self type
+    InherentImpl(Vec<String>),       // Names in the self type
+    ImplMember(usize, String),       // Index of the impl node, and member name
+    UseLeaf(String, Option<String>), // Name brought into scope, and the original of `as _`
+}
+
+struct Graph {
+    nodes: Vec<Node>,
+    refs: Vec<Vec<String>>,       // Identifiers each node uses once it's kept
+    labels: Vec<String>,          // How each node shows up in the report
+    roots: HashSet<usize>,        // Nodes kept from the start
+    local_names: HashSet<String>, // Everything the crate defines itself
+}
+
+pub struct Shaken {
+    pub content: TokenStream,
+    pub removed: Vec<String>, // The removed items, e.g. `fn crate::utils::unused`
+}
+
+pub fn tree_shake(content: TokenStream, annotations: &Annotations) -> syn::Result<Shaken> {
+    let file: syn::File = syn::parse2(content)?;
+    let library = !file
+        .items
+        .iter()
+        .any(|item| matches!(item, Item::Fn(item_fn) if item_fn.sig.ident == "main"));
+
+    let mut graph = Graph {
+        nodes: Vec::new(),
+        refs: Vec::new(),
+        labels: Vec::new(),
+        roots: HashSet::new(),
+        local_names: HashSet::new(),
+    };
+    graph.collect(&file.items, "crate", library);
+    let kept = graph.reachable();
+
+    let mut removed = Vec::new();
+    let mut cursor = 0;
+    let items = rebuild(
+        file.items,
+        &kept,
+        &graph,
+        &mut cursor,
+        &mut removed,
+        annotations,
+    );
+    let file = syn::File { items, ..file };
+
+    Ok(Shaken {
+        content: file.into_token_stream(),
+        removed,
+    })
+}
+
+impl Graph {
+    fn add(&mut self, node: Node, tokens: &dyn ToTokens, label: String) -> usize {
+        let mut refs = Vec::new();
+        collect_idents(tokens.to_token_stream(), &mut refs);
+        self.add_with_refs(node, refs, label)
+    }
+
+    fn add_with_refs(&mut self, node: Node, refs: Vec<String>, label: String) -> usize {
+        self.nodes.push(node);
+        self.refs.push(refs);
+        self.labels.push(label);
+        self.nodes.len() - 1
+    }
+
+    // Walks the items in the same order as `rebuild`, so node indices line up
+    fn collect(&mut self, items: &[Item], module_path: &str, library: bool) {
+        for item in items {
+            let root = match item_vis(item) {
+                Some(Visibility::Public(_)) => library,
+                _ => false,
+            } || is_exported(item, library);
+            let label = |kind: &str, name: &dyn std::fmt::Display| {
+                format!("{} {}::{}", kind, module_path, name)
+            };
+
+            let id = match item {
+                Item::Mod(item_mod) => {
+                    self.local_names.insert(item_mod.ident.to_string());
+                    if let Some((_, items)) = &item_mod.content {
+                        let path = format!("{}::{}", module_path, item_mod.ident);
+                        self.collect(items, &path, library);
+                    }
+                    continue;
+                }
+                Item::Use(item_use) => {
+                    for (name, original, path) in use_leaves(&item_use.tree) {
+                        let id =
+                            self.add_with_refs(Node::UseLeaf(name, original), path, String::new());
+                        if root {
+                            self.roots.insert(id);
+                        }
+                    }
+                    continue;
+                }
+                Item::Impl(item_impl) => {
+                    self.collect_impl(item_impl, module_path, library);
+                    continue;
+                }
+                Item::Fn(item_fn) => {
+                    let name = item_fn.sig.ident.to_string();
+                    let main = module_path == "crate" && name == "main";
+                    let id = self.add_named(&name, item, label("fn", &name));
+                    if main {
+                        self.roots.insert(id);
+                    }
+                    id
+                }
+                Item::Struct(item) => {
+                    self.add_named(&item.ident.to_string(), item, label("struct", &item.ident))
+                }
+                Item::Enum(item) => {
+                    self.add_named(&item.ident.to_string(), item, label("enum", &item.ident))
+                }
+                Item::Union(item) => {
+                    self.add_named(&item.ident.to_string(), item, label("union", &item.ident))
+                }
+                Item::Type(item) => {
+                    self.add_named(&item.ident.to_string(), item, label("type", &item.ident))
+                }
+                Item::Static(item) => {
+                    self.add_named(&item.ident.to_string(), item, label("static", &item.ident))
+                }
+                Item::Const(item) if item.ident != "_" => {
+                    self.add_named(&item.ident.to_string(), item, label("const", &item.ident))
+                }
+                Item::Macro(item_macro) if item_macro.ident.is_some() => {
+                    let name = item_macro.ident.as_ref().unwrap().to_string();
+                    self.add_named(&name, item, label("macro", &name))
+                }
+                Item::Trait(item_trait) => {
+                    let name = item_trait.ident.to_string();
+                    let members = item_trait
+                        .items
+                        .iter()
+                        .filter_map(|member| match member {
+                            syn::TraitItem::Fn(f) => Some(f.sig.ident.to_string()),
+                            syn::TraitItem::Const(c) => Some(c.ident.to_string()),
+                            syn::TraitItem::Type(t) => Some(t.ident.to_string()),
+                            _ => None,
+                        })
+                        .collect();
+                    self.local_names.insert(name.clone());
+                    self.add(
+                        Node::Trait(name.clone(), members),
+                        item,
+                        label("trait", &name),
+                    )
+                }
+                _ => self.add(Node::Always, item, String::new()),
+            };
+            if root {
+                self.roots.insert(id);
+            }
+        }
+    }
+
+    fn add_named(&mut self, name: &str, item: &dyn ToTokens, label: String) -> usize {
+        self.local_names.insert(name.to_string());
+        self.add(Node::Named(name.to_string()), item, label)
+    }
+
+    fn collect_impl(&mut self, item_impl: &ItemImpl, module_path: &str, library: bool) {
+        let mut self_names = Vec::new();
+        collect_idents(item_impl.self_ty.to_token_stream(), &mut self_names);
+        let self_name = type_name(&item_impl.self_ty);
+
+        if let Some((_, trait_path, _)) = &item_impl.trait_ {
+            let trait_name = trait_path
+                .segments
+                .last()
+                .map(|segment| segment.ident.to_string())
+                .unwrap_or_default();
+            let label = format!("impl {} for {}::{}", trait_name, module_path, self_name);
+            self.add(Node::TraitImpl(trait_name, self_names), item_impl, label);
+            return;
+        }
+
+        // Inherent impls are shaken member by member; the header carries the
+        // generics and the self type
+        let header = ItemImpl {
+            items: Vec::new(),
+            ..item_impl.clone()
+        };
+        let impl_id = self.add(Node::InherentImpl(self_names), &header, String::new());
+        for member in &item_impl.items {
+            let name = match member {
+                ImplItem::Fn(f) => f.sig.ident.to_string(),
+                ImplItem::Const(c) => c.ident.to_string(),
+                ImplItem::Type(t) => t.ident.to_string(),
+                _ => {
+                    let id = self.add(
+                        Node::ImplMember(impl_id, String::new()),
+                        member,
+                        String::new(),
+                    );
+                    self.roots.insert(id);
+                    continue;
+                }
+            };
+            let public =
+                matches!(member, ImplItem::Fn(f) if matches!(f.vis, Visibility::Public(_)));
+            let label = format!("fn {}::{}::{}", module_path, self_name, name);
+            let id = self.add(Node::ImplMember(impl_id, name), member, label);
+            if library && public {
+                self.roots.insert(id);
+            }
+        }
+    }
+
+    fn reachable(&self) -> Vec<bool> {
+        let mut kept = vec![false; self.nodes.len()];
+        let mut used: HashSet<String> = HashSet::new();
+        let mut kept_traits: HashSet<String> = HashSet::new();
+
+        loop {
+            let mut changed = false;
+            for (id, node) in self.nodes.iter().enumerate() {
+                if kept[id] {
+                    continue;
+                }
+                let keep = self.roots.contains(&id)
+                    || match node {
+                        Node::Always => true,
+                        Node::Named(name) => used.contains(name),
+                        Node::Trait(name, members) => {
+                            used.contains(name)
+                                || members.iter().any(|member| used.contains(member))
+                        }
+                        Node::TraitImpl(trait_name, self_names) => {
+                            (!self.local_names.contains(trait_name)
+                                || kept_traits.contains(trait_name))
+                                && self.self_type_used(self_names, &used)
+                        }
+                        Node::InherentImpl(self_names) => self.self_type_used(self_names, &used),
+                        Node::ImplMember(impl_id, name) => kept[*impl_id] && used.contains(name),
+                        Node::UseLeaf(_, Some(original)) => {
+                            !self.local_names.contains(original) || kept_traits.contains(original)
+                        }
+                        // Imports of names from elsewhere may be traits that method
+                        // calls need in scope, so only the crate's own names are shaken
+                        Node::UseLeaf(name, None) => {
+                            name == "*" || !self.local_names.contains(name) || used.contains(name)
+                        }
+                    };
+                if keep {
+                    kept[id] = true;
+                    changed = true;
+                    used.extend(self.refs[id].iter().cloned());
+                    if let Node::Trait(name, _) = node {
+                        kept_traits.insert(name.clone());
+                    }
+                }
+            }
+            if !changed {
+                return kept;
+            }
+        }
+    }
+
+    // Impls for types the crate doesn't define (`impl Trait for Vec<u8>`) always
+    // count as used; impls for its own types only once one of them is used
+    fn self_type_used(&self, self_names: &[String], used: &HashSet<String>) -> bool {
+        let mut local = self_names
+            .iter()
+            .filter(|name| self.local_names.contains(*name))
+            .peekable();
+        local.peek().is_none() || local.any(|name| used.contains(name))
+    }
+}
+
+fn rebuild(
+    items: Vec<Item>,
+    kept: &[bool],
+    graph: &Graph,
+    cursor: &mut usize,
+    removed: &mut Vec<String>,
+    annotations: &Annotations,
+) -> Vec<Item> {
+    let mut result = Vec::new();
+
+    for item in items {
+        match item {
+            Item::Mod(mut item_mod) => {
+                if let Some((brace, items)) = item_mod.content.take() {
+                    let items = rebuild(items, kept, graph, cursor, removed, annotations);
+                    // Modules left with nothing but their file comments go too
+                    let empty = items.iter().all(|item| {
+                        matches!(item, Item::Macro(item_macro) if annotations.is_marker(item_macro))
+                    });
+                    if empty {
+                        drop_item_marker(&mut result, annotations);
+                        continue;
+                    }
+                    item_mod.content = Some((brace, items));
+                }
+                result.push(Item::Mod(item_mod));
+            }
+            Item::Use(mut item_use) => {
+                let leaves: Vec<bool> = use_leaves(&item_use.tree)
+                    .iter()
+                    .map(|_| kept[next_node(cursor)])
+                    .collect();
+                let mut leaves = leaves.into_iter();
+                if let Some(tree) = prune_use_tree(item_use.tree, &mut leaves) {
+                    item_use.tree = tree;
+                    result.push(Item::Use(item_use));
+                }
+            }
+            Item::Impl(mut item_impl) if item_impl.trait_.is_none() => {
+                let impl_id = next_node(cursor);
+                let mut members = Vec::new();
+                for member in item_impl.items {
+                    let id = next_node(cursor);
+                    if kept[id] {
+                        members.push(member);
+                    } else if !graph.labels[id].is_empty() {
+                        removed.push(graph.labels[id].clone());
+                    }
+                }
+                if kept[impl_id] && !members.is_empty() {
+                    item_impl.items = members;
+                    result.push(Item::Impl(item_impl));
+                } else {
+                    drop_item_marker(&mut result, annotations);
+                }
+            }
+            item => {
+                let id = next_node(cursor);
+                if kept[id] {
+                    result.push(item);
+                } else {
+                    removed.push(graph.labels[id].clone());
+                    drop_item_marker(&mut result, annotations);
+                }
+            }
+        }
+    }
+    result
+}
+
+// A removed item takes the placeholder of its annotation along
+fn drop_item_marker(items: &mut Vec<Item>, annotations: &Annotations) {
+    if matches!(items.last(), Some(Item::Macro(item_macro)) if annotations.is_item_marker(item_macro))
+    {
+        items.pop();
+    }
+}
+
+fn item_vis(item: &Item) -> Option<&Visibility> {
+    match item {
+        Item::Const(item) => Some(&item.vis),
+        Item::Enum(item) => Some(&item.vis),
+        Item::Fn(item) => Some(&item.vis),
+        Item::Static(item) => Some(&item.vis),
+        Item::Struct(item) => Some(&item.vis),
+        Item::Trait(item) => Some(&item.vis),
+        Item::Type(item) => Some(&item.vis),
+        Item::Union(item) => Some(&item.vis),
+        Item::Use(item) => Some(&item.vis),
+        _ => None,
+    }
+}
+
+// Items the outside world can reach without going through the crate's paths
+fn is_exported(item: &Item, library: bool) -> bool {
+    let attrs = match item {
+        Item::Fn(item) => &item.attrs,
+        Item::Static(item) => &item.attrs,
+        Item::Macro(item) => {
+            return library
+                && item
+                    .attrs
+                    .iter()
+                    .any(|attr| attr.path().is_ident("macro_export"))
+        }
+        _ => return false,
+    };
+    // Also matches the `#[unsafe(no_mangle)]` spelling
+    attrs.iter().any(|attr| {
+        let attr = attr.to_token_stream().to_string();
+        attr.contains("no_mangle") || attr.contains("export_name")
+    })
+}
+
+type UseLeaf = (String, Option<String>, Vec<String>);
+
+// Names a `use` brings into scope, one per leaf, in tree order, with the path
+// leading to each. `use Trait as _` brings in no name, so it remembers the trait.
+fn use_leaves(tree: &UseTree) -> Vec<UseLeaf> {
+    let mut leaves = Vec::new();
+    collect_use_leaves(tree, &mut Vec::new(), &mut leaves);
+    leaves
+}
+
+fn collect_use_leaves(tree: &UseTree, path: &mut Vec<String>, leaves: &mut Vec<UseLeaf>) {
+    let mut leaf = |name: String, original: Option<String>, last: Option<String>| {
+        let mut path = path.clone();
+        path.extend(last);
+        leaves.push((name, original, path));
+    };
+    match tree {
+        UseTree::Path(use_path) => {
+            path.push(use_path.ident.to_string());
+            collect_use_leaves(&use_path.tree, path, leaves);
+            path.pop();
+        }
+        // `use foo::{self}` brings `foo` into scope
+        UseTree::Name(name) if name.ident == "self" => {
+            leaf(path.last().cloned().unwrap_or_default(), None, None)
+        }
+        UseTree::Name(name) => leaf(name.ident.to_string(), None, Some(name.ident.to_string())),
+        UseTree::Rename(rename) if rename.rename == "_" => {
+            let original = rename.ident.to_string();
+            leaf(String::from("_"), Some(original.clone()), Some(original))
+        }
+        UseTree::Rename(rename) => leaf(
+            rename.rename.to_string(),
+            None,
+            Some(rename.ident.to_string()),
+        ),
+        UseTree::Glob(_) => leaf(String::from("*"), None, None),
+        UseTree::Group(group) => {
+            for tree in &group.items {
+                collect_use_leaves(tree, path, leaves);
+            }
+        }
+    }
+}
+
+// Drop the leaves of a use tree that weren't kept, and groups left empty
+fn prune_use_tree(tree: UseTree, kept: &mut impl Iterator<Item = bool>) -> Option<UseTree> {
+    match tree {
+        UseTree::Path(mut path) => {
+            let tree = prune_use_tree(*path.tree, kept)?;
+            path.tree = Box::new(tree);
+            Some(UseTree::Path(path))
+        }
+        UseTree::Group(mut group) => {
+            let items: Vec<UseTree> = std::mem::take(&mut group.items)
+                .into_iter()
+                .filter_map(|tree| prune_use_tree(tree, kept))
+                .collect();
+            if items.is_empty() {
+                return None;
+            }
+            group.items = items.into_iter().collect();
+            Some(UseTree::Group(group))
+        }
+        tree => kept.next().unwrap_or(true).then_some(tree),
+    }
+}
+
+fn type_name(ty: &Type) -> String {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident.to_string())
+            .unwrap_or_default(),
+        Type::Reference(reference) => type_name(&reference.elem),
+        ty => ty.to_token_stream().to_string(),
+    }
+}
+
+pub fn collect_idents(tokens: TokenStream, idents: &mut Vec<String>) {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => idents.push(ident.to_string()),
+            TokenTree::Group(group) => collect_idents(group.stream(), idents),
+            _ => {}
+        }
+    }
+}
+
+fn next_node(cursor: &mut usize) -> usize {
+    *cursor += 1;
+    *cursor - 1
+}
@@ -0,0 +1,259 @@
+// `cargo rustmerge unmerge`: a merged file split back into source files. Every
+// module block that came from a file of its own opens with that file's comment
+// (`// utils/mod.rs`), which is all it takes to put it back where it was, edits
+// made to the merged file included.
+//
+// A block with a file comment goes to its file, minus the comment and the
+// indentation the merge gave it, and its `mod foo { ... }` becomes `mod foo;`
+// again. Blocks without one are inline modules and stay as they are. Comments
+// kept by `--preserve-comments` can look like file comments too, so one only
+// counts if it names a file rustc would find the module in by its name, or its
+// declaration's `#[path]` points at it, or the merge marked it `(#[path])`. A
+// marked file gets a `#[path]` attribute on its declaration. Item comments left
+// by `--annotate-items` are dropped.
+
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use syn::spanned::Spanned;
+use syn::Item;
+
+use crate::resolver::{path_attr, ModuleDir};
+use crate::splice::span_offset;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    pub path: PathBuf,  // Relative to the source directory the crate was merged from
+    pub module: String, // Path of the module in the crate, like `crate::utils::fmt`
+    pub text: String,
+}
+
+// The files of the merged crate `merged`, each followed by the files of the
+// modules it declares, in declaration order
+pub fn unmerge(merged: &str) -> Result<Vec<SourceFile>> {
+    let item_comment = Regex::new(r"^\s*// \S+\.rs:\d+(-\d+)?$").unwrap();
+    let merged: String = strip_script_header(merged)
+        .split_inclusive('\n')
+        .filter(|line| !item_comment.is_match(line.trim_end()))
+        .collect();
+
+    // The root file's comment comes before its first item, after any inner attributes
+    let (body, offset) = parse(&merged)?;
+    let head_end = body
+        .items
+        .first()
+        .map_or(merged.len(), |item| offset + item.span().byte_range().start);
+    let (comment, path, _) = file_comment(&merged[..head_end]).context(
+        "No file comment found at the top of the merged file; unmerging needs the comments a merge leaves, which --minify strips",
+    )?;
+    let root = format!("{}{}", &merged[..comment.start], &merged[comment.end..]);
+
+    let path = PathBuf::from(path);
+    let module = String::from("crate");
+    let mut files = Vec::new();
+    let text = split_file(&root, &ModuleDir::crate_root(&path), &module, &mut files)?;
+    add_file(&mut files, 0, SourceFile { path, module, text })?;
+    Ok(files)
+}
+
+// `text` with its file modules taken out into `files`
+fn split_file(
+    text: &str,
+    dir: &ModuleDir,
+    module: &str,
+    files: &mut Vec<SourceFile>,
+) -> Result<String> {
+    let (file, offset) = parse(text)?;
+    let body = &text[offset..];
+    let mut edits = Vec::new();
+    collect_edits(&file.items, body, dir, module, files, &mut edits)?;
+
+    let mut result = text[..offset].to_string();
+    let mut cursor = 0;
+    for (range, replacement) in edits {
+        result.push_str(&body[cursor..range.start]);
+        result.push_str(&replacement);
+        cursor = range.end;
+    }
+    result.push_str(&body[cursor..]);
+    Ok(result)
+}
+
+// Edits come out in source order
+fn collect_edits(
+    items: &[Item],
+    body: &str,
+    dir: &ModuleDir,
+    module: &str,
+    files: &mut Vec<SourceFile>,
+    edits: &mut Vec<(Range<usize>, String)>,
+) -> Result<()> {
+    for item in items {
+        let Item::Mod(item_mod) = item else {
+            continue;
+        };
+        let Some((brace, inner_items)) = &item_mod.content else {
+            continue;
+        };
+        let open = brace.span.open().byte_range().end;
+        let close = brace.span.close().byte_range();
+        let head_end = inner_items
+            .first()
+            .map_or(close.start, |item| item.span().byte_range().start);
+
+        let module = format!("{}::{}", module, item_mod.ident);
+        let (default_path, secondary_path) = dir.candidates(&item_mod.ident);
+        let declared_path = path_attr(&item_mod.attrs).map(|path| dir.path.join(path));
+        let Some((comment, path, marked)) = file_comment(&body[open..head_end])
+            .map(|(comment, path, marked)| (comment, PathBuf::from(path), marked))
+            .filter(|(_, path, marked)| {
+                *marked
+                    || *path == default_path
+                    || *path == secondary_path
+                    || declared_path.as_ref() == Some(path)
+            })
+        else {
+            let dir = dir.inline(&item_mod.ident, &item_mod.attrs);
+            collect_edits(inner_items, body, &dir, &module, files, edits)?;
+            continue;
+        };
+
+        let indent = indentation(&body[open + comment.start..]);
+        let inner = format!(
+            "{}{}",
+            &body[open..open + comment.start],
+            &body[open + comment.end..close.start]
+        );
+        // The file goes before those of its own modules
+        let index = files.len();
+        let text = split_file(
+            &dedent(&inner, indent),
+            &dir.file_dir(&item_mod.ident, &path),
+            &module,
+            files,
+        )?;
+
+        if marked && declared_path.is_none() {
+            let start = item_mod.span().byte_range().start;
+            let line_start = body[..start].rfind('\n').map_or(0, |i| i + 1);
+            edits.push((
+                start..start,
+                format!(
+                    "#[path = {:?}]\n{}",
+                    relative_path(&dir.path, &path).to_string_lossy(),
+                    indentation(&body[line_start..])
+                ),
+            ));
+        }
+        edits.push((
+            item_mod.ident.span().byte_range().end..close.end,
+            ";".to_string(),
+        ));
+        add_file(files, index, SourceFile { path, module, text })?;
+    }
+    Ok(())
+}
+
+// A file can show up more than once, e.g. under two `#[cfg]`s, as long as it
+// reads the same each time
+fn add_file(files: &mut Vec<SourceFile>, index: usize, file: SourceFile) -> Result<()> {
+    match files.iter().find(|existing| existing.path == file.path) {
+        Some(existing) if existing.text == file.text => Ok(()),
+        Some(_) => Err(anyhow::anyhow!(
+            "{:?} is in the merged file twice, with different contents",
+            file.path
+        )),
+        None => {
+            files.insert(index, file);
+            Ok(())
+        }
+    }
+}
+
+// The shebang and manifest `--format cargo-script` and `rust-script` put in
+// front of the code are the merge's own, not part of any file
+fn strip_script_header(merged: &str) -> &str {
+    let Some((shebang, rest)) = merged.split_once('\n') else {
+        return merged;
+    };
+    if !shebang.starts_with("#!") || shebang.starts_with("#![") {
+        return merged;
+    }
+    let end = if rest.starts_with("---") {
+        "\n---\n"
+    } else if rest.starts_with("//! ```cargo\n") {
+        "\n//! ```\n"
+    } else {
+        return merged;
+    };
+    rest.split_once(end)
+        .map_or(merged, |(_, code)| code.trim_start_matches('\n'))
+}
+
+// The parsed file, and where the text its spans are relative to starts
+fn parse(text: &str) -> Result<(syn::File, usize)> {
+    let file = syn::parse_file(text).context("Failed to parse the merged file")?;
+    let offset = span_offset(text, &file);
+    Ok((file, offset))
+}
+
+// The line range of the first `// path.rs` comment in `head`, the path, and
+// whether it's marked `(#[path])`. Item comments end in a line number, and
+// paths with whitespace in them aren't told apart from prose, so neither match.
+fn file_comment(head: &str) -> Option<(Range<usize>, String, bool)> {
+    let file_comment = Regex::new(r"^\s*// (\S+\.rs)( \(#\[path\]\))?$").unwrap();
+    let mut start = 0;
+    for line in head.split_inclusive('\n') {
+        if let Some(captures) = file_comment.captures(line.trim_end()) {
+            return Some((
+                start..start + line.len(),
+                captures[1].to_string(),
+                captures.get(2).is_some(),
+            ));
+        }
+        start += line.len();
+    }
+    None
+}
+
+fn indentation(line: &str) -> &str {
+    let end = line
+        .find(|c: char| c != ' ' && c != '\t')
+        .unwrap_or(line.len());
+    &line[..end]
+}
+
+// The contents of a block as a file of their own: without the rest of the
+// opening brace's line, the indentation of the closing one, or `indent`
+fn dedent(inner: &str, indent: &str) -> String {
+    let inner = match inner.split_once('\n') {
+        Some((first, rest)) if first.trim().is_empty() => rest,
+        _ => inner,
+    };
+    let inner = inner.trim_end_matches([' ', '\t']);
+
+    let mut text: String = inner
+        .split_inclusive('\n')
+        .map(|line| line.strip_prefix(indent).unwrap_or(line))
+        .collect();
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text
+}
+
+// `to` as a path from the directory `from`, both relative to the same place
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut path: PathBuf = from[common..]
+        .iter()
+        .map(|_| Component::ParentDir)
+        .collect();
+    path.extend(&to[common..]);
+    path
+}
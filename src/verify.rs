@@ -0,0 +1,114 @@
+// `--verify`: type-check the merged output as the root of a crate of its own,
+// with the package's dependencies, and report what the compiler finds at the
+// original location of the code rather than in the merged file.
+//
+// The check runs offline, so it only uses dependencies that are already in the
+// local cargo cache or a configured vendor directory. The package's lockfile is
+// copied over, so that they resolve to the versions the package builds with.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::annotate::{LineMap, Location};
+use crate::manifest::Target;
+use crate::standalone::{self, StandaloneManifest};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub level: String,   // `error`, `warning`, ...
+    pub message: String, // With its code, e.g. `error[E0425]: cannot find value ...`
+    pub line: usize,     // Where it is in the merged code
+    pub column: usize,
+    pub origin: Option<Location>, // Where the code is in the original files
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.level == "error"
+    }
+}
+
+// Check `code`, the merged `target`, and return the compiler's diagnostics for it.
+// Fails if the check couldn't run, e.g. when a dependency isn't available offline.
+pub fn check(
+    standalone: &StandaloneManifest,
+    target: &Target,
+    code: &str,
+    lines: &LineMap,
+    lockfile: Option<&Path>,
+) -> Result<Vec<Diagnostic>> {
+    let scratch = scratch_dir();
+    let crate_dir = scratch.join(format!("{}-{}", target.name, std::process::id()));
+    let result = check_in(
+        &crate_dir, &scratch, standalone, target, code, lines, lockfile,
+    );
+    let _ = fs::remove_dir_all(&crate_dir);
+    result
+}
+
+fn check_in(
+    crate_dir: &Path,
+    scratch: &Path,
+    standalone: &StandaloneManifest,
+    target: &Target,
+    code: &str,
+    lines: &LineMap,
+    lockfile: Option<&Path>,
+) -> Result<Vec<Diagnostic>> {
+    let root_file = standalone::root_file(target);
+    fs::create_dir_all(crate_dir.join("src"))
+        .with_context(|| format!("Failed to create {:?}", crate_dir))?;
+    fs::write(crate_dir.join("Cargo.toml"), standalone.cargo_toml(target))?;
+    fs::write(crate_dir.join(root_file), code)?;
+    if let Some(lockfile) = lockfile {
+        fs::copy(lockfile, crate_dir.join("Cargo.lock"))
+            .with_context(|| format!("Failed to copy {:?}", lockfile))?;
+    }
+
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = Command::new(cargo)
+        .args(["check", "--offline", "--quiet", "--message-format=short"])
+        .arg("--target-dir")
+        .arg(scratch.join("target"))
+        .current_dir(crate_dir)
+        .output()
+        .context("Failed to run cargo check")?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    // `src/main.rs:3:13: error[E0308]: mismatched types`
+    let pattern = format!(r"^{}:(\d+):(\d+): (\w+)(.*)$", regex::escape(root_file));
+    let re = Regex::new(&pattern).unwrap();
+    let located = lines.locate_all(code);
+    let diagnostics: Vec<Diagnostic> = stderr
+        .lines()
+        .filter_map(|line| re.captures(line))
+        .map(|caps| {
+            let line = caps[1].parse().unwrap_or(0);
+            Diagnostic {
+                level: caps[3].to_string(),
+                message: format!("{}{}", &caps[3], &caps[4]),
+                line,
+                column: caps[2].parse().unwrap_or(0),
+                origin: located.get(line.wrapping_sub(1)).cloned().flatten(),
+            }
+        })
+        .collect();
+
+    if !output.status.success() && !diagnostics.iter().any(Diagnostic::is_error) {
+        return Err(anyhow::anyhow!(
+            "cargo check failed before getting to the merged code:\n{}",
+            stderr.trim_end()
+        ));
+    }
+    Ok(diagnostics)
+}
+
+// Kept between runs, so dependencies are only built once
+fn scratch_dir() -> PathBuf {
+    env::temp_dir().join("rustmerge-verify")
+}